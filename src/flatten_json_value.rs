@@ -1,11 +1,554 @@
 #![allow(clippy::unit_arg)]
+pub use crate::serde::flattened::guess_type::{Guess, GuessTypes};
 use {
-    std::borrow::Cow,
+    base64::prelude::{BASE64_STANDARD, Engine},
+    std::{
+        borrow::Cow,
+        collections::{BTreeMap, BTreeSet},
+        fmt::Write,
+    },
     tap::{Pipe, Tap},
 };
 
-const ARR_PFX: &str = "idx-";
-const JOIN_TAG: &str = "__";
+/// Describes how a [`FieldPath`] is rendered into a single flat key and parsed back.
+///
+/// Both sides of a round trip must use the same config, otherwise the keys produced
+/// by flattening won't be recognised when unflattening / deserializing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlattenConfig {
+    /// put between path segments, e.g. `user__address__city`
+    pub separator: Cow<'static, str>,
+    /// put before an array index, e.g. `items__idx-0`
+    pub array_prefix: Cow<'static, str>,
+    /// put after an array index, e.g. `items.[0]` when prefix is `[` and suffix is `]`
+    pub array_suffix: Cow<'static, str>,
+    /// used to escape field names which contain the separator or look like an array index,
+    /// e.g. a field named `a__b` is rendered as `a\__b`. Must not be a part of the separator,
+    /// see [`FlattenConfig::validate`].
+    pub escape: char,
+    /// fixed number of elements for the sequence at a path. Shorter sequences are padded
+    /// with empty cells, longer ones are an error. The path is rendered without array
+    /// indices, so `orders__items` applies to every `orders__idx-N__items`.
+    pub array_widths: BTreeMap<String, usize>,
+    /// sequences of scalars written into a single delimited cell, e.g. `a;b;c`
+    pub multi_value: Option<MultiValue>,
+    /// objects and arrays at this many path segments (or deeper) are written as
+    /// compact JSON text in a single cell, e.g. `metadata` => `{"a":[1,2]}` when set to `1`
+    pub max_depth: Option<usize>,
+    /// objects and arrays at these paths (rendered without array indices) are written as
+    /// compact JSON text in a single cell
+    pub opaque_paths: BTreeSet<String>,
+    /// empty objects and arrays are written as `{}` / `[]` cells instead of disappearing,
+    /// so e.g. `Some(vec![])` and `None` can be told apart. Strings which are exactly
+    /// `{}` or `[]` become ambiguous.
+    pub empty_markers: bool,
+    /// trailing array elements with nothing but empty cells are dropped when reading, e.g.
+    /// the padding of [`UnknownKeys::Extend`](crate::nested_csv::write::UnknownKeys::Extend)
+    /// rows. Always done for paths in [`FlattenConfig::array_widths`], otherwise `[Some(1), None]`
+    /// reads back as is.
+    pub trim_arrays: bool,
+    /// types a cell may be read as when the target doesn't ask for a specific one,
+    /// e.g. `#[serde(untagged)]` enums or `serde_json::Value`
+    pub guess_types: GuessTypes,
+    /// how many more times a record is read, with some of its guessed cells read as text,
+    /// when its type rejects a guess. Serde buffers `#[serde(flatten)]` fields and internally
+    /// tagged / untagged enums, so e.g. a `String` field of theirs holding `42` gets an integer
+    /// and fails. `0` reads every record once.
+    pub text_retries: usize,
+    /// put between the elements of a tuple map key, e.g. `(1, "a")` => `1|a`. Escaped
+    /// with `\` when a part of an element.
+    pub key_delimiter: char,
+    /// cells written for NaN and the infinities, which JSON has no numbers for
+    pub non_finite: NonFinite,
+    /// cell written for `None` / null instead of an empty one, e.g. `NULL` or `\N`.
+    /// Cells holding it are read as null.
+    pub null_token: Option<Cow<'static, str>>,
+    /// what an empty cell means when reading
+    pub empty_cells: EmptyCells,
+    /// name of a column (e.g. `inner__present`) written as `true` for every `Some` holding
+    /// a struct, map, sequence or tuple. Reading honours it when deciding between `Some` and
+    /// `None`, so `Some(Inner { a: None })` no longer reads back as `None`. Only the content
+    /// of an `Option` gets the marker, so it can't be a field name of it (writing fails).
+    pub presence_marker: Option<Cow<'static, str>>,
+    /// how bytes (`serialize_bytes`, e.g. `serde_bytes::ByteBuf`) are put into cells.
+    /// A plain `Vec<u8>` or `[u8; N]` serializes as a sequence of numbers and keeps one
+    /// column per byte, it needs `#[serde(with = "serde_bytes")]` to be encoded.
+    pub bytes: BytesEncoding,
+}
+
+/// A [`FlattenConfig`] whose headers or cells couldn't be read back, see [`FlattenConfig::validate`]
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("escape char '{escape}' is a part of the separator '{separator}'")]
+    EscapeInSeparator { escape: char, separator: String },
+    #[error("empty cells are empty strings but there's no null token, so null can't be written")]
+    EmptyStringWithoutNullToken,
+}
+
+/// Meaning of an empty cell, see [`FlattenConfig::empty_cells`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmptyCells {
+    /// `None` / null, so `Some(String::new())` reads back as `None`
+    #[default]
+    Null,
+    /// an empty string, only the [`FlattenConfig::null_token`] is null (so one has to be set)
+    EmptyString,
+    /// reading an empty cell is an error
+    Error,
+}
+
+/// Cell representation of bytes, see [`FlattenConfig::bytes`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BytesEncoding {
+    /// same as serde_json, an array of numbers - one `idx-N` column per byte
+    #[default]
+    Array,
+    /// a single cell of standard, padded base64
+    Base64,
+    /// a single cell of lowercase hex, either case is read
+    Hex,
+}
+
+impl BytesEncoding {
+    /// the cell for `bytes`, `None` for [`BytesEncoding::Array`]
+    pub fn encode(&self, bytes: &[u8]) -> Option<String> {
+        match self {
+            BytesEncoding::Array => None,
+            BytesEncoding::Base64 => Some(BASE64_STANDARD.encode(bytes)),
+            BytesEncoding::Hex => bytes
+                .iter()
+                .fold(String::with_capacity(bytes.len() * 2), |out, byte| {
+                    out.tap_mut(|out| write!(out, "{byte:02x}").expect("writing to string"))
+                })
+                .pipe(Some),
+        }
+    }
+
+    /// the bytes held by a cell, `None` if it isn't valid. [`BytesEncoding::Array`] takes the
+    /// text of the cell as is.
+    pub fn decode(&self, cell: &str) -> Option<Vec<u8>> {
+        match self {
+            BytesEncoding::Array => Some(cell.as_bytes().to_vec()),
+            BytesEncoding::Base64 => BASE64_STANDARD.decode(cell).ok(),
+            BytesEncoding::Hex => match cell.len() % 2 {
+                0 => (0..cell.len())
+                    .step_by(2)
+                    .map(|idx| {
+                        cell.get(idx..idx + 2)
+                            .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                    })
+                    .collect(),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl FlattenConfig {
+    pub const DEFAULT: Self = Self {
+        separator: Cow::Borrowed("__"),
+        array_prefix: Cow::Borrowed("idx-"),
+        array_suffix: Cow::Borrowed(""),
+        escape: '\\',
+        array_widths: BTreeMap::new(),
+        multi_value: None,
+        max_depth: None,
+        opaque_paths: BTreeSet::new(),
+        empty_markers: false,
+        trim_arrays: false,
+        guess_types: GuessTypes::DEFAULT,
+        text_retries: 0,
+        key_delimiter: '|',
+        non_finite: NonFinite::DEFAULT,
+        null_token: None,
+        empty_cells: EmptyCells::Null,
+        presence_marker: None,
+        bytes: BytesEncoding::Array,
+    };
+
+    /// see [`FlattenConfig::array_widths`]
+    pub fn with_array_width(self, path: impl Into<String>, width: usize) -> Self {
+        self.tap_mut(|config| {
+            config.array_widths.insert(path.into(), width);
+        })
+    }
+
+    /// see [`FlattenConfig::multi_value`]
+    pub fn with_multi_value(self, multi_value: MultiValue) -> Self {
+        Self {
+            multi_value: Some(multi_value),
+            ..self
+        }
+    }
+
+    /// see [`FlattenConfig::max_depth`]
+    pub fn with_max_depth(self, max_depth: usize) -> Self {
+        Self {
+            max_depth: Some(max_depth),
+            ..self
+        }
+    }
+
+    /// see [`FlattenConfig::opaque_paths`]
+    pub fn with_opaque_path(self, path: impl Into<String>) -> Self {
+        self.tap_mut(|config| {
+            config.opaque_paths.insert(path.into());
+        })
+    }
+
+    /// see [`FlattenConfig::empty_markers`]
+    pub fn with_empty_markers(self) -> Self {
+        Self {
+            empty_markers: true,
+            ..self
+        }
+    }
+
+    /// see [`FlattenConfig::trim_arrays`]
+    pub fn with_trimmed_arrays(self) -> Self {
+        Self {
+            trim_arrays: true,
+            ..self
+        }
+    }
+
+    /// see [`FlattenConfig::key_delimiter`]
+    pub fn with_key_delimiter(self, key_delimiter: char) -> Self {
+        Self {
+            key_delimiter,
+            ..self
+        }
+    }
+
+    /// see [`FlattenConfig::non_finite`]
+    pub fn with_non_finite(self, non_finite: NonFinite) -> Self {
+        Self { non_finite, ..self }
+    }
+
+    /// see [`FlattenConfig::null_token`]
+    pub fn with_null_token(self, null_token: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            null_token: Some(null_token.into()),
+            ..self
+        }
+    }
+
+    /// see [`FlattenConfig::empty_cells`]
+    pub fn with_empty_cells(self, empty_cells: EmptyCells) -> Self {
+        Self {
+            empty_cells,
+            ..self
+        }
+    }
+
+    /// see [`FlattenConfig::presence_marker`]
+    pub fn with_presence_marker(self, presence_marker: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            presence_marker: Some(presence_marker.into()),
+            ..self
+        }
+    }
+
+    /// see [`FlattenConfig::bytes`]
+    pub fn with_bytes(self, bytes: BytesEncoding) -> Self {
+        Self { bytes, ..self }
+    }
+
+    /// Checks that what is written with this config can be read back with it.
+    /// Readers and writers call it before using the config.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        match (
+            self.separator.contains(self.escape),
+            self.empty_cells,
+            &self.null_token,
+        ) {
+            (true, _, _) => Err(ConfigError::EscapeInSeparator {
+                escape: self.escape,
+                separator: self.separator.to_string(),
+            }),
+            // `None` would be written as an empty cell and read back as an empty string
+            (false, EmptyCells::EmptyString, None) => Err(ConfigError::EmptyStringWithoutNullToken),
+            (false, _, _) => Ok(()),
+        }
+    }
+
+    /// the cell written for `None` / null
+    pub fn null_cell(&self) -> &str {
+        self.null_token.as_deref().unwrap_or_default()
+    }
+
+    /// the cell holds `None` / null, see [`FlattenConfig::null_token`] and [`FlattenConfig::empty_cells`]
+    pub fn is_null(&self, cell: &str) -> bool {
+        self.null_token.as_deref() == Some(cell)
+            || (cell.is_empty() && self.empty_cells == EmptyCells::Null)
+    }
+
+    /// see [`FlattenConfig::guess_types`]
+    pub fn with_guess_types(self, guess_types: GuessTypes) -> Self {
+        Self {
+            guess_types,
+            ..self
+        }
+    }
+
+    /// see [`FlattenConfig::text_retries`]
+    pub fn with_text_retries(self, text_retries: usize) -> Self {
+        Self {
+            text_retries,
+            ..self
+        }
+    }
+
+    /// an object or array at `path` is kept as JSON text instead of being flattened
+    pub fn is_opaque(&self, path: &FieldPath<'_>) -> bool {
+        !path.0.is_empty()
+            && (self
+                .max_depth
+                .is_some_and(|max_depth| path.0.len() >= max_depth)
+                || (!self.opaque_paths.is_empty()
+                    && self.opaque_paths.contains(&self.without_indices(path))))
+    }
+
+    /// fixed number of elements of the sequence at `path`, if any
+    pub fn array_width(&self, path: &FieldPath<'_>) -> Option<usize> {
+        match self.array_widths.is_empty() {
+            true => None,
+            false => self
+                .without_indices(path)
+                .pipe(|path| self.array_widths.get(&path).copied()),
+        }
+    }
+
+    /// delimited cell encoding of the sequence at `path`, if any
+    pub fn multi_value(&self, path: &FieldPath<'_>) -> Option<&MultiValue> {
+        self.multi_value.as_ref().filter(|multi_value| {
+            multi_value.paths.is_empty() || multi_value.paths.contains(&self.without_indices(path))
+        })
+    }
+
+    /// renders the path with array indices skipped, used for per-path settings
+    fn without_indices(&self, path: &FieldPath<'_>) -> String {
+        path.0
+            .iter()
+            .filter(|segment| matches!(segment, Segment::Field(_)))
+            .map(|segment| segment.render(self))
+            .collect::<Vec<_>>()
+            .join(self.separator.as_ref())
+    }
+
+    /// appends an already rendered (escaped) segment to an already rendered prefix
+    pub fn join(&self, prefix: &str, raw_segment: &str) -> String {
+        match prefix.is_empty() {
+            true => raw_segment.to_string(),
+            false => format!("{prefix}{}{raw_segment}", self.separator),
+        }
+    }
+
+    /// splits a flat key into rendered (still escaped) segments, escaped separators are skipped
+    pub fn split<'k>(&self, key: &'k str) -> Vec<&'k str> {
+        let separator = self.separator.as_ref();
+        let mut segments = Vec::new();
+        let (mut start, mut idx) = (0, 0);
+        while let Some(current) = key[idx..].chars().next() {
+            let rest = &key[idx..];
+            if current == self.escape {
+                idx += current.len_utf8()
+                    + rest[current.len_utf8()..]
+                        .chars()
+                        .next()
+                        .map_or(0, char::len_utf8);
+            } else if !separator.is_empty() && rest.starts_with(separator) {
+                segments.push(&key[start..idx]);
+                idx += separator.len();
+                start = idx;
+            } else {
+                idx += current.len_utf8();
+            }
+        }
+        segments.tap_mut(|segments| segments.push(&key[start..]))
+    }
+
+    fn parse_idx(&self, raw: &str) -> Option<usize> {
+        raw.strip_prefix(self.array_prefix.as_ref())
+            .and_then(|idx| idx.strip_suffix(self.array_suffix.as_ref()))
+            .and_then(|idx| idx.parse::<usize>().ok())
+    }
+
+    fn escape_field<'f>(&self, field: &'f str) -> Cow<'f, str> {
+        let separator = self.separator.as_ref();
+        let needs_escape = |rest: &str| {
+            rest.starts_with(self.escape)
+                || (!separator.is_empty()
+                    && (rest.starts_with(separator) || separator.starts_with(rest)))
+        };
+        let looks_like_idx = self.parse_idx(field).is_some();
+        match looks_like_idx
+            || field
+                .char_indices()
+                .any(|(idx, _)| needs_escape(&field[idx..]))
+        {
+            false => Cow::Borrowed(field),
+            true => field
+                .char_indices()
+                .fold(
+                    String::with_capacity(field.len() + 2),
+                    |mut out, (idx, current)| {
+                        if (idx == 0 && looks_like_idx) || needs_escape(&field[idx..]) {
+                            out.push(self.escape);
+                        }
+                        out.tap_mut(|out| out.push(current))
+                    },
+                )
+                .pipe(Cow::Owned),
+        }
+    }
+
+    fn unescape<'f>(&self, raw: &'f str) -> Cow<'f, str> {
+        match raw.contains(self.escape) {
+            false => Cow::Borrowed(raw),
+            true => raw
+                .chars()
+                .fold(
+                    (String::with_capacity(raw.len()), false),
+                    |(out, escaped), current| match !escaped && current == self.escape {
+                        true => (out, true),
+                        false => (out.tap_mut(|out| out.push(current)), false),
+                    },
+                )
+                .0
+                .pipe(Cow::Owned),
+        }
+    }
+}
+
+/// Encoding of a sequence of scalars as a single cell, e.g. `a;b;c` instead of
+/// `tags__idx-0,tags__idx-1,tags__idx-2`.
+///
+/// Sequences with non-scalar elements keep using one column per element.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiValue {
+    pub delimiter: char,
+    /// put before a delimiter or escape char which is a part of a value
+    pub escape: char,
+    /// paths (rendered without array indices) using this encoding, every sequence when empty
+    pub paths: BTreeSet<String>,
+}
+
+impl MultiValue {
+    pub fn new(delimiter: char) -> Self {
+        Self {
+            delimiter,
+            escape: '\\',
+            paths: BTreeSet::new(),
+        }
+    }
+
+    /// see [`MultiValue::paths`]
+    pub fn with_path(self, path: impl Into<String>) -> Self {
+        self.tap_mut(|multi_value| {
+            multi_value.paths.insert(path.into());
+        })
+    }
+
+    /// joins already rendered cells, escaping the delimiter
+    pub fn join<'v>(&self, values: impl IntoIterator<Item = &'v str>) -> String {
+        values
+            .into_iter()
+            .enumerate()
+            .fold(String::new(), |out, (idx, value)| {
+                out.tap_mut(|out| {
+                    if idx > 0 {
+                        out.push(self.delimiter);
+                    }
+                    value.chars().for_each(|current| {
+                        if current == self.delimiter || current == self.escape {
+                            out.push(self.escape);
+                        }
+                        out.push(current)
+                    })
+                })
+            })
+    }
+
+    /// splits a cell produced by [`MultiValue::join`], an empty cell holds no values
+    pub fn split<'c>(&self, cell: &'c str) -> Vec<Cow<'c, str>> {
+        let mut values = Vec::new();
+        if cell.is_empty() {
+            return values;
+        }
+        let (mut start, mut escaped) = (0, None::<String>);
+        let mut chars = cell.char_indices();
+        while let Some((idx, current)) = chars.next() {
+            if current == self.escape {
+                let unescaped = escaped.get_or_insert_with(|| cell[start..idx].to_string());
+                if let Some((_, next)) = chars.next() {
+                    unescaped.push(next);
+                }
+            } else if current == self.delimiter {
+                values.push(
+                    escaped
+                        .take()
+                        .map_or(Cow::Borrowed(&cell[start..idx]), Cow::Owned),
+                );
+                start = idx + current.len_utf8();
+            } else if let Some(unescaped) = escaped.as_mut() {
+                unescaped.push(current);
+            }
+        }
+        values.tap_mut(|values| {
+            values.push(escaped.map_or(Cow::Borrowed(&cell[start..]), Cow::Owned))
+        })
+    }
+}
+
+/// Tokens for the floats JSON can't represent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NonFinite {
+    pub nan: Cow<'static, str>,
+    pub infinity: Cow<'static, str>,
+    pub neg_infinity: Cow<'static, str>,
+}
+
+impl NonFinite {
+    pub const DEFAULT: Self = Self {
+        nan: Cow::Borrowed("NaN"),
+        infinity: Cow::Borrowed("inf"),
+        neg_infinity: Cow::Borrowed("-inf"),
+    };
+
+    /// the token for `value`, if it isn't finite
+    pub fn token(&self, value: f64) -> Option<&str> {
+        match value {
+            _ if value.is_nan() => Some(&self.nan),
+            f64::INFINITY => Some(&self.infinity),
+            f64::NEG_INFINITY => Some(&self.neg_infinity),
+            _ => None,
+        }
+    }
+
+    /// the value of a cell holding one of the tokens
+    pub fn parse(&self, cell: &str) -> Option<f64> {
+        [
+            (&self.nan, f64::NAN),
+            (&self.infinity, f64::INFINITY),
+            (&self.neg_infinity, f64::NEG_INFINITY),
+        ]
+        .into_iter()
+        .find_map(|(token, value)| (token == cell).then_some(value))
+    }
+}
+
+impl Default for NonFinite {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl Default for FlattenConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Segment<'a> {
@@ -16,19 +559,22 @@ pub enum Segment<'a> {
 #[allow(clippy::to_string_trait_impl)]
 impl ToString for Segment<'_> {
     fn to_string(&self) -> String {
-        match self {
-            Segment::Idx(idx) => format!("{ARR_PFX}{idx}"),
-            Segment::Field(cow) => cow.as_ref().to_string(),
-        }
+        self.render(&FlattenConfig::DEFAULT)
     }
 }
 impl<'a> Segment<'a> {
-    #[expect(clippy::should_implement_trait, reason = "this can never fail")]
-    pub fn from_str(idx: &'a str) -> Segment<'a> {
-        idx.strip_prefix(ARR_PFX)
-            .and_then(|idx| idx.parse::<usize>().ok())
+    /// parses a single rendered segment, undoing the escaping done by [`Segment::render`]
+    pub fn from_str(idx: &'a str, config: &FlattenConfig) -> Segment<'a> {
+        config
+            .parse_idx(idx)
             .map(Segment::Idx)
-            .unwrap_or_else(|| idx.pipe(Cow::Borrowed).pipe(Segment::Field))
+            .unwrap_or_else(|| config.unescape(idx).pipe(Segment::Field))
+    }
+    pub fn render(&self, config: &FlattenConfig) -> String {
+        match self {
+            Segment::Idx(idx) => format!("{}{idx}{}", config.array_prefix, config.array_suffix),
+            Segment::Field(cow) => config.escape_field(cow).into_owned(),
+        }
     }
     pub fn to_owned(&self) -> Segment<'static> {
         match self {
@@ -49,6 +595,21 @@ impl<'a> Segment<'a> {
 pub struct FieldPath<'a>(Vec<Segment<'a>>);
 
 impl<'a> FieldPath<'a> {
+    pub fn parse(key: &'a str, config: &FlattenConfig) -> Self {
+        config
+            .split(key)
+            .into_iter()
+            .map(|segment| Segment::from_str(segment, config))
+            .collect::<Vec<_>>()
+            .pipe(FieldPath)
+    }
+    pub fn render(&self, config: &FlattenConfig) -> String {
+        self.0
+            .iter()
+            .map(|segment| segment.render(config))
+            .collect::<Vec<_>>()
+            .join(config.separator.as_ref())
+    }
     pub fn pop_start(mut self) -> Option<(Segment<'a>, Self)> {
         match self.0.len() {
             0 => None,
@@ -65,6 +626,9 @@ impl<'a> FieldPath<'a> {
     pub fn join(&self, segment: Segment<'a>) -> Self {
         self.clone().tap_mut(|p| p.0.push(segment))
     }
+    pub fn segments(&self) -> &[Segment<'a>] {
+        &self.0
+    }
     pub fn as_ref<'b>(&'b self) -> FieldPath<'b> {
         FieldPath(self.0.iter().map(|b| b.as_ref()).collect())
     }
@@ -79,4 +643,6 @@ where
 }
 
 pub mod flatten;
+pub mod schema;
+pub mod serializer;
 pub mod unflatten;
@@ -1,26 +1,78 @@
 use {
-    super::{FieldPath, JOIN_TAG, Segment, boxed_iter},
-    itertools::Itertools,
+    super::{FieldPath, FlattenConfig, Segment, boxed_iter, serializer::FlatSerializer},
+    serde::Serialize,
     serde_json::Value,
     std::{borrow::Cow, iter::once},
     tap::Pipe,
 };
 
-pub fn flattened_iter<'prefix>(prefix: FieldPath<'prefix>, value: Value) -> impl Iterator<Item = (FieldPath<'static>, Value)> {
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("two different paths were flattened into the same key: '{key}'")]
+    KeyCollision { key: String },
+    #[error("'{key}' has more than the {width} elements allowed for it")]
+    ArrayTooLong { key: String, width: usize },
+    #[error("map key must be a scalar, a unit variant or a tuple of those")]
+    KeyMustBeAString,
+    #[error("{0}")]
+    Custom(String),
+}
+
+impl serde::ser::Error for Error {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Error::Custom(msg.to_string())
+    }
+}
+
+type Result<T> = std::result::Result<T, self::Error>;
+
+static DEFAULT_CONFIG: FlattenConfig = FlattenConfig::DEFAULT;
+
+pub fn flattened_iter<'prefix>(
+    prefix: FieldPath<'prefix>,
+    value: Value,
+) -> impl Iterator<Item = (FieldPath<'static>, Value)> {
+    flattened_iter_with_config(prefix, value, &DEFAULT_CONFIG)
+}
+
+/// Same as [`flattened_iter`], objects and arrays at [`FlattenConfig::is_opaque`] paths
+/// are yielded as compact JSON text
+pub fn flattened_iter_with_config<'prefix, 'c>(
+    prefix: FieldPath<'prefix>,
+    value: Value,
+    config: &'c FlattenConfig,
+) -> impl Iterator<Item = (FieldPath<'static>, Value)> + 'c {
     match value {
+        value @ (Value::Array(_) | Value::Object(_)) if config.is_opaque(&prefix) => {
+            once((prefix.to_owned(), Value::String(value.to_string()))).pipe(boxed_iter)
+        }
+        Value::Array(arr) if arr.is_empty() && config.empty_markers && !prefix.0.is_empty() => {
+            once((prefix.to_owned(), Value::String("[]".into()))).pipe(boxed_iter)
+        }
+        Value::Object(map) if map.is_empty() && config.empty_markers && !prefix.0.is_empty() => {
+            once((prefix.to_owned(), Value::String("{}".into()))).pipe(boxed_iter)
+        }
         Value::Array(arr) => arr
             .into_iter()
             .enumerate()
             .flat_map({
-                let prefix = prefix.clone();
-                move |(idx, value)| flattened_iter(prefix.clone().join(Segment::Idx(idx)), value)
+                let prefix = prefix.to_owned();
+                move |(idx, value)| {
+                    flattened_iter_with_config(prefix.join(Segment::Idx(idx)), value, config)
+                }
             })
             .pipe(boxed_iter),
         Value::Object(map) => map
             .into_iter()
             .flat_map({
-                let prefix = prefix.clone();
-                move |(key, value)| flattened_iter(prefix.clone().join(Segment::Field(Cow::Owned(key))), value)
+                let prefix = prefix.to_owned();
+                move |(key, value)| {
+                    flattened_iter_with_config(
+                        prefix.join(Segment::Field(Cow::Owned(key))),
+                        value,
+                        config,
+                    )
+                }
             })
             .pipe(boxed_iter),
         other => once((prefix.to_owned(), other)).pipe(boxed_iter),
@@ -28,13 +80,24 @@ pub fn flattened_iter<'prefix>(prefix: FieldPath<'prefix>, value: Value) -> impl
     .pipe(boxed_iter)
 }
 
-pub fn flattened(value: serde_json::Value) -> serde_json::Map<String, serde_json::Value> {
-    flattened_iter(Default::default(), value)
-        .map(|(k, v)| (k.0.iter().map(|k| k.to_string()).join(JOIN_TAG), v))
-        .collect()
+pub fn flattened(value: serde_json::Value) -> Result<serde_json::Map<String, serde_json::Value>> {
+    flattened_with_config(value, &FlattenConfig::DEFAULT)
+}
+
+pub fn flattened_with_config(
+    value: serde_json::Value,
+    config: &FlattenConfig,
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    FlatSerializer::new(config, serde_json::Map::new()).pipe(|mut serializer| {
+        value
+            .serialize(&mut serializer)
+            .map(|()| serializer.into_sink())
+    })
 }
 
-pub fn assert_flattened(value: serde_json::Value) -> Result<serde_json::Map<String, serde_json::Value>, serde_json::Value> {
+pub fn assert_flattened(
+    value: serde_json::Value,
+) -> std::result::Result<serde_json::Map<String, serde_json::Value>, serde_json::Value> {
     match value {
         Value::Object(map) => Ok(map),
         other => Err(other),
@@ -43,7 +106,9 @@ pub fn assert_flattened(value: serde_json::Value) -> Result<serde_json::Map<Stri
 
 #[cfg(test)]
 mod tests {
-    use {super::*, serde_json::json, tap::Tap};
+    use {super::*, crate::flatten_json_value::MultiValue, serde_json::json, tap::Tap};
+
+    const JOIN_TAG: &str = "__";
 
     #[test]
     fn test_flatten_simple() {
@@ -52,7 +117,7 @@ mod tests {
             "age": 30
         });
 
-        let result = flattened(input);
+        let result = flattened(input).unwrap();
         assert_eq!(result.get("name").unwrap(), &json!("John"));
         assert_eq!(result.get("age").unwrap(), &json!(30));
     }
@@ -70,7 +135,7 @@ mod tests {
             "active": true
         });
 
-        let result = flattened(input);
+        let result = flattened(input).unwrap();
         assert_eq!(
             (&result)
                 .tap(|r| println!("{r:#?}"))
@@ -94,4 +159,146 @@ mod tests {
         );
         assert_eq!(result.get("active").unwrap(), &json!(true));
     }
+
+    #[test]
+    fn test_flatten_custom_path_syntax() {
+        let config = FlattenConfig {
+            separator: ".".into(),
+            array_prefix: "[".into(),
+            array_suffix: "]".into(),
+            ..Default::default()
+        };
+        let input = json!({
+            "user": {
+                "tags": ["a", "b"],
+            },
+        });
+
+        let result = flattened_with_config(input.clone(), &config).unwrap();
+        assert_eq!(result.get("user.tags.[0]").unwrap(), &json!("a"));
+        assert_eq!(result.get("user.tags.[1]").unwrap(), &json!("b"));
+        assert_eq!(
+            crate::flatten_json_value::unflatten::unflattened_with_config(result.into(), &config)
+                .unwrap(),
+            input
+        );
+    }
+
+    #[test]
+    fn test_flatten_escapes_ambiguous_field_names() {
+        let input = json!({
+            "a__b": 1,
+            "a": { "b": 2 },
+            "idx-3": 3,
+            "tags": ["x"],
+            "trailing_": { "_": 4 },
+            "back\\slash": 5,
+        });
+
+        let result = flattened(input.clone()).unwrap();
+        assert_eq!(result.len(), 6, "{result:#?}");
+        assert_eq!(result.get("a\\__b").unwrap(), &json!(1));
+        assert_eq!(result.get("a__b").unwrap(), &json!(2));
+        assert_eq!(result.get("\\idx-3").unwrap(), &json!(3));
+        assert_eq!(result.get("tags__idx-0").unwrap(), &json!("x"));
+        assert_eq!(
+            crate::flatten_json_value::unflatten::unflattened(result.into()).unwrap(),
+            input
+        );
+    }
+
+    #[test]
+    fn test_segment_round_trip() {
+        let config = FlattenConfig::DEFAULT;
+        [
+            "a__b", "idx-3", "idx-", "_", "x_", "__", "\\", "a\\__b", "plain",
+        ]
+        .into_iter()
+        .for_each(|field| {
+            let rendered = Segment::Field(field.into()).render(&config);
+            assert_eq!(
+                config.split(&rendered),
+                vec![rendered.as_str()],
+                "{field} => {rendered}"
+            );
+            assert_eq!(
+                Segment::from_str(&rendered, &config),
+                Segment::Field(field.into())
+            );
+        });
+    }
+
+    #[test]
+    fn test_multi_value_cells() {
+        let multi_value = MultiValue::new(';');
+        let values = ["a", "b;c", "d\\e", ""];
+        let cell = multi_value.join(values);
+        assert_eq!(cell, "a;b\\;c;d\\\\e;");
+        assert_eq!(multi_value.split(&cell), values);
+        assert!(multi_value.split("").is_empty());
+
+        let config = FlattenConfig::default().with_multi_value(multi_value.with_path("tags"));
+        let result = flattened_with_config(
+            json!({ "tags": ["x", "y"], "other": ["z"], "nested": [{ "tags": [1, 2] }] }),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(result.get("tags").unwrap(), &json!("x;y"));
+        assert_eq!(result.get("other__idx-0").unwrap(), &json!("z"));
+        assert_eq!(result.get("nested__idx-0__tags__idx-1").unwrap(), &json!(2));
+    }
+
+    #[test]
+    fn test_opaque_subtrees_are_json_text() {
+        let input = json!({
+            "id": 1,
+            "user": { "address": { "city": "NYC" }, "tags": ["a"] },
+            "metadata": { "free": ["form", { "x": null }] },
+        });
+        let config = FlattenConfig::default()
+            .with_max_depth(2)
+            .with_opaque_path("metadata");
+        let expected = json!({
+            "id": 1,
+            "user__address": r#"{"city":"NYC"}"#,
+            "user__tags": r#"["a"]"#,
+            "metadata": r#"{"free":["form",{"x":null}]}"#,
+        });
+
+        assert_eq!(
+            Value::Object(flattened_with_config(input.clone(), &config).unwrap()),
+            expected
+        );
+        assert_eq!(
+            flattened_iter_with_config(FieldPath::default(), input, &config)
+                .map(|(path, value)| (path.render(&config), value))
+                .collect::<serde_json::Map<_, _>>()
+                .pipe(Value::Object),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_empty_containers_survive_with_markers() {
+        let input = json!({
+            "tags": [],
+            "meta": {},
+            "nested": { "list": [[], [1]] },
+            "kept": [1],
+        });
+        let config = FlattenConfig::default().with_empty_markers();
+
+        let result = flattened_with_config(input.clone(), &config).unwrap();
+        assert_eq!(result.get("tags").unwrap(), &json!("[]"));
+        assert_eq!(result.get("meta").unwrap(), &json!("{}"));
+        assert_eq!(result.get("nested__list__idx-0").unwrap(), &json!("[]"));
+        assert_eq!(
+            crate::flatten_json_value::unflatten::unflattened_with_config(result.into(), &config)
+                .unwrap(),
+            input
+        );
+
+        // without markers the empty containers disappear
+        assert!(!flattened(input).unwrap().contains_key("tags"));
+    }
 }
@@ -0,0 +1,947 @@
+//! Shape of a type, traced from its [`Deserialize`](serde::Deserialize) impl without needing a value.
+//!
+//! The tracer answers every request of the type with a placeholder (`0`, `""`, `Some`, one
+//! element per sequence...) and records what was asked for. Every enum variant is explored
+//! by re-running the trace until no enum has unvisited variants left.
+//!
+//! Parts asking for `deserialize_any` (internally tagged and untagged enums,
+//! `#[serde(flatten)]`) can't be answered with a placeholder the type accepts, they are
+//! recorded as [`Schema::Any`]. Types validating their value reject the placeholder too (`0`
+//! for a `NonZeroU32`, `""` for an `IpAddr` or a date), after it's recorded which leaf they
+//! ask for. Either way the run fails, so it is repeated with the struct fields holding such
+//! parts traced last, for the other fields to be seen. A value rejected anywhere else (e.g.
+//! by a newtype checking the number it got) is an error naming its path.
+
+use {
+    super::{FieldPath, FlattenConfig, Segment},
+    indexmap::IndexMap,
+    serde::{
+        Deserializer,
+        de::{
+            self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess,
+            SeqAccess, VariantAccess, Visitor,
+        },
+    },
+    std::collections::{HashMap, HashSet},
+    tap::Tap,
+};
+
+/// paths deeper than this are only traced as far as needed to produce a value
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("tracing went deeper than {MAX_DEPTH} levels at '{path}', is the type recursive?")]
+    TooDeep { path: String },
+    #[error("{0}")]
+    Custom(String),
+    #[error("the type rejected the value traced at '{path}': {message}")]
+    Rejected { path: String, message: String },
+}
+
+impl de::Error for Error {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Error::Custom(msg.to_string())
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schema {
+    /// a single cell
+    Leaf,
+    /// `()` and unit structs, a single (empty) cell. Unit variants share the cell of their enum.
+    Unit,
+    Option(Box<Schema>),
+    Struct(IndexMap<&'static str, Schema>),
+    /// tuples, tuple structs and tuple variants - fixed number of elements
+    Tuple(Vec<Schema>),
+    /// variable number of elements, holds the schema of a single element
+    Seq(Box<Schema>),
+    /// arbitrary keys, holds the schema of a single value
+    Map(Box<Schema>),
+    Enum(IndexMap<&'static str, Schema>),
+    /// anything goes (`deserialize_any`, ignored values, cut off recursion)
+    Any,
+}
+
+impl Schema {
+    /// Traces the shape of `T`
+    pub fn of<T: DeserializeOwned>() -> Result<Self> {
+        let mut state = TraceState::default();
+        let mut schema = Schema::Any;
+        loop {
+            state.pending = false;
+            state.rejected = false;
+            let rejecting = state.rejecting.len();
+            let mut run = Schema::Any;
+            let traced = T::deserialize(Tracer {
+                slot: &mut run,
+                state: &mut state,
+            });
+            schema.merge(run);
+            let failed = match traced {
+                Ok(_) => false,
+                // the placeholder of a leaf or a dynamic part was rejected
+                Err(Error::Custom(_) | Error::Rejected { .. }) if state.rejected => true,
+                Err(error) => return Err(error),
+            };
+            // new rejecting parts move the fields holding them last, worth another run
+            if !state.pending && !(failed && state.rejecting.len() > rejecting) {
+                return Ok(schema);
+            }
+        }
+    }
+
+    /// Flattened headers of every column with a fixed place in the type.
+    ///
+    /// The parts listed by [`Schema::dynamic_paths`] have no fixed set of columns, so they
+    /// produce none (besides the cell for [`FlattenConfig::empty_markers`]).
+    pub fn headers(&self, config: &FlattenConfig) -> Vec<String> {
+        Vec::new().tap_mut(|headers| self.collect_headers(&FieldPath::default(), config, headers))
+    }
+
+    fn collect_headers(
+        &self,
+        path: &FieldPath<'static>,
+        config: &FlattenConfig,
+        headers: &mut Vec<String>,
+    ) {
+        match self {
+            Schema::Struct(_)
+            | Schema::Tuple(_)
+            | Schema::Seq(_)
+            | Schema::Map(_)
+            | Schema::Any
+                if config.is_opaque(path) =>
+            {
+                headers.push(path.render(config))
+            }
+            Schema::Leaf | Schema::Unit => headers.push(path.render(config)),
+            Schema::Option(inner) => {
+                if let Some(marker) = config.presence_marker.as_ref().filter(|_| {
+                    !path.0.is_empty() && !config.is_opaque(path) && inner.is_container()
+                }) {
+                    headers.push(path.join(Segment::Field(marker.clone())).render(config));
+                }
+                inner.collect_headers(path, config, headers)
+            }
+            Schema::Struct(fields) => fields.iter().for_each(|(field, schema)| {
+                schema.collect_headers(&path.join(Segment::Field((*field).into())), config, headers)
+            }),
+            Schema::Tuple(elements) => elements.iter().enumerate().for_each(|(idx, schema)| {
+                schema.collect_headers(&path.join(Segment::Idx(idx)), config, headers)
+            }),
+            Schema::Enum(variants) => {
+                if variants.values().any(|schema| schema == &Schema::Unit) {
+                    headers.push(path.render(config));
+                }
+                variants
+                    .iter()
+                    .filter(|(_, schema)| *schema != &Schema::Unit)
+                    .for_each(|(variant, schema)| {
+                        schema.collect_headers(
+                            &path.join(Segment::Field((*variant).into())),
+                            config,
+                            headers,
+                        )
+                    })
+            }
+            Schema::Seq(element) if element.is_scalar() && config.multi_value(path).is_some() => {
+                headers.push(path.render(config))
+            }
+            Schema::Seq(element) => match config.array_width(path).unwrap_or_default() {
+                0 if config.empty_markers => headers.push(path.render(config)),
+                width => (0..width).for_each(|idx| {
+                    element.collect_headers(&path.join(Segment::Idx(idx)), config, headers)
+                }),
+            },
+            Schema::Map(_) if config.empty_markers => headers.push(path.render(config)),
+            Schema::Map(_) | Schema::Any => {}
+        }
+    }
+
+    /// Rendered paths of the parts without a fixed set of columns: maps, [`Schema::Any`] and
+    /// sequences without a [`FlattenConfig::array_widths`] entry, unless kept in a single cell
+    /// (see [`FlattenConfig::is_opaque`] and [`FlattenConfig::multi_value`]).
+    pub fn dynamic_paths(&self, config: &FlattenConfig) -> Vec<String> {
+        Vec::new().tap_mut(|paths| self.collect_dynamic(&FieldPath::default(), config, paths))
+    }
+
+    fn collect_dynamic(
+        &self,
+        path: &FieldPath<'static>,
+        config: &FlattenConfig,
+        paths: &mut Vec<String>,
+    ) {
+        match self {
+            _ if config.is_opaque(path) => {}
+            Schema::Leaf | Schema::Unit => {}
+            Schema::Option(inner) => inner.collect_dynamic(path, config, paths),
+            Schema::Struct(fields) | Schema::Enum(fields) => {
+                fields.iter().for_each(|(field, schema)| {
+                    schema.collect_dynamic(
+                        &path.join(Segment::Field((*field).into())),
+                        config,
+                        paths,
+                    )
+                })
+            }
+            Schema::Tuple(elements) => elements.iter().enumerate().for_each(|(idx, schema)| {
+                schema.collect_dynamic(&path.join(Segment::Idx(idx)), config, paths)
+            }),
+            Schema::Seq(element) if element.is_scalar() && config.multi_value(path).is_some() => {}
+            Schema::Seq(element) => match config.array_width(path) {
+                Some(width) => (0..width).for_each(|idx| {
+                    element.collect_dynamic(&path.join(Segment::Idx(idx)), config, paths)
+                }),
+                None => paths.push(path.render(config)),
+            },
+            Schema::Map(_) | Schema::Any => paths.push(path.render(config)),
+        }
+    }
+
+    /// Whether a column with the flattened `path` has a place in the type, e.g. to spot
+    /// columns the type doesn't know about. Maps and [`Schema::Any`] take every path below them.
+    pub fn covers(&self, path: &FieldPath<'_>, config: &FlattenConfig) -> bool {
+        self.covers_from(path, 0, config)
+    }
+
+    /// `path` from `depth` on is the part below `self`
+    fn covers_from(&self, path: &FieldPath<'_>, depth: usize, config: &FlattenConfig) -> bool {
+        let here = || FieldPath(path.0[..depth].to_vec());
+        match (self, path.0.get(depth)) {
+            (Schema::Any, _) => true,
+            (Schema::Option(_), Some(Segment::Field(field)))
+                if config.presence_marker.as_deref() == Some(field.as_ref())
+                    && path.0.len() == depth + 1 =>
+            {
+                true
+            }
+            (Schema::Option(inner), _) => inner.covers_from(path, depth, config),
+            (Schema::Leaf | Schema::Unit, None) => true,
+            (Schema::Enum(variants), None) => {
+                variants.values().any(|schema| schema == &Schema::Unit)
+            }
+            (Schema::Seq(element), None)
+                if element.is_scalar() && config.multi_value(&here()).is_some() =>
+            {
+                true
+            }
+            (_, None) => config.empty_markers || config.is_opaque(&here()),
+            (Schema::Struct(fields) | Schema::Enum(fields), Some(Segment::Field(field))) => fields
+                .get(field.as_ref())
+                .is_some_and(|schema| schema.covers_from(path, depth + 1, config)),
+            (Schema::Tuple(elements), Some(Segment::Idx(idx))) => elements
+                .get(*idx)
+                .is_some_and(|schema| schema.covers_from(path, depth + 1, config)),
+            (Schema::Seq(element), Some(Segment::Idx(_))) | (Schema::Map(element), Some(_)) => {
+                element.covers_from(path, depth + 1, config)
+            }
+            _ => false,
+        }
+    }
+
+    /// `path` with its field names replaced by the ones of the type they are the `same` as,
+    /// e.g. `Customer__ZIP` => `customer__zip` when compared case-insensitively. Segments
+    /// the type has no match for are kept as they are.
+    pub fn resolve(
+        &self,
+        path: &FieldPath<'_>,
+        same: &dyn Fn(&str, &str) -> bool,
+    ) -> FieldPath<'static> {
+        path.to_owned()
+            .tap_mut(|resolved| self.resolve_from(&mut resolved.0, same))
+    }
+
+    fn resolve_from(&self, segments: &mut [Segment<'static>], same: &dyn Fn(&str, &str) -> bool) {
+        let Some((first, rest)) = segments.split_first_mut() else {
+            return;
+        };
+        match (self, &*first) {
+            (Schema::Option(inner), _) => inner.resolve_from(segments, same),
+            (Schema::Struct(fields) | Schema::Enum(fields), Segment::Field(field)) => {
+                let found = fields
+                    .get_key_value(field.as_ref())
+                    .or_else(|| fields.iter().find(|(name, _)| same(name, field)));
+                if let Some((name, schema)) = found {
+                    *first = Segment::Field((*name).into());
+                    schema.resolve_from(rest, same)
+                }
+            }
+            (Schema::Tuple(elements), Segment::Idx(idx)) => {
+                if let Some(schema) = elements.get(*idx) {
+                    schema.resolve_from(rest, same)
+                }
+            }
+            (Schema::Seq(element), Segment::Idx(_)) | (Schema::Map(element), _) => {
+                element.resolve_from(rest, same)
+            }
+            _ => {}
+        }
+    }
+
+    /// fits into a single cell
+    fn is_scalar(&self) -> bool {
+        match self {
+            Schema::Leaf | Schema::Unit => true,
+            Schema::Option(inner) => inner.is_scalar(),
+            Schema::Enum(variants) => variants.values().all(|schema| schema == &Schema::Unit),
+            _ => false,
+        }
+    }
+
+    /// gets a presence marker when inside an `Option`, see [`FlattenConfig::presence_marker`]
+    fn is_container(&self) -> bool {
+        matches!(
+            self,
+            Schema::Struct(_) | Schema::Tuple(_) | Schema::Seq(_) | Schema::Map(_)
+        )
+    }
+
+    /// Combines the results of two traces, `other` fills in whatever `self` didn't see
+    fn merge(&mut self, other: Schema) {
+        match (self, other) {
+            (_, Schema::Any) => {}
+            (this @ Schema::Any, other) => *this = other,
+            (Schema::Option(this), Schema::Option(other))
+            | (Schema::Seq(this), Schema::Seq(other))
+            | (Schema::Map(this), Schema::Map(other)) => this.merge(*other),
+            (Schema::Struct(this), Schema::Struct(other))
+            | (Schema::Enum(this), Schema::Enum(other)) => other
+                .into_iter()
+                .for_each(|(name, other)| this.entry(name).or_insert(Schema::Any).merge(other)),
+            (Schema::Tuple(this), Schema::Tuple(other)) => this
+                .iter_mut()
+                .zip(other)
+                .for_each(|(this, other)| this.merge(other)),
+            _ => {}
+        }
+    }
+}
+
+#[derive(Default)]
+struct TraceState {
+    path: FieldPath<'static>,
+    /// next variant to trace, per enum path
+    variants: HashMap<FieldPath<'static>, usize>,
+    /// some enum visited during this run still has unvisited variants
+    pending: bool,
+    /// names of the structs currently being traced, used to spot recursion
+    structs: Vec<&'static str>,
+    /// how many of the structs being traced are already on the stack
+    recursion: usize,
+    /// paths answered by `deserialize_any` or whose placeholder was rejected, in any run
+    rejecting: HashSet<FieldPath<'static>>,
+    /// a placeholder was (or likely will be) rejected during this run
+    rejected: bool,
+}
+
+impl TraceState {
+    /// no more optional / repeated data is produced, so that recursive types terminate
+    fn cut(&self) -> bool {
+        self.recursion > 0 || self.path.0.len() > MAX_DEPTH
+    }
+
+    /// there is a part whose placeholder gets rejected at or below `path`
+    fn holds_rejecting(&self, path: &FieldPath<'_>) -> bool {
+        self.rejecting
+            .iter()
+            .any(|rejecting| rejecting.0.starts_with(&path.0))
+    }
+
+    /// the placeholder given at the current path was rejected
+    fn reject<T>(&mut self, value: Result<T>) -> Result<T> {
+        value.inspect_err(|_| {
+            self.rejecting.insert(self.path.clone());
+            self.rejected = true;
+        })
+    }
+
+    fn nested<T>(
+        &mut self,
+        segment: Segment<'static>,
+        trace: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        self.path.0.push(segment);
+        let result = match self.path.0.len() > 2 * MAX_DEPTH {
+            true => Err(Error::TooDeep {
+                path: self.path.render(&FlattenConfig::DEFAULT),
+            }),
+            false => trace(self).map_err(|error| match error {
+                Error::Custom(message) => Error::Rejected {
+                    path: self.path.render(&FlattenConfig::DEFAULT),
+                    message,
+                },
+                error => error,
+            }),
+        };
+        self.path.0.pop();
+        result
+    }
+}
+
+struct Tracer<'t> {
+    slot: &'t mut Schema,
+    state: &'t mut TraceState,
+}
+
+impl Tracer<'_> {
+    fn leaf(self, schema: Schema) {
+        *self.slot = schema;
+    }
+}
+
+/// the leaf is recorded before its placeholder is given, which the type may reject
+macro_rules! trace_leaf {
+    ($($method:ident => $visit:ident($placeholder:expr),)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+                *self.slot = Schema::Leaf;
+                self.state.reject(visitor.$visit($placeholder))
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for Tracer<'_> {
+    type Error = Error;
+
+    trace_leaf! {
+        deserialize_bool => visit_bool(false),
+        deserialize_i8 => visit_i8(0),
+        deserialize_i16 => visit_i16(0),
+        deserialize_i32 => visit_i32(0),
+        deserialize_i64 => visit_i64(0),
+        deserialize_i128 => visit_i128(0),
+        deserialize_u8 => visit_u8(0),
+        deserialize_u16 => visit_u16(0),
+        deserialize_u32 => visit_u32(0),
+        deserialize_u64 => visit_u64(0),
+        deserialize_u128 => visit_u128(0),
+        deserialize_f32 => visit_f32(0.0),
+        deserialize_f64 => visit_f64(0.0),
+        deserialize_char => visit_char(' '),
+        deserialize_str => visit_str(""),
+        deserialize_string => visit_str(""),
+        deserialize_bytes => visit_bytes(&[]),
+        deserialize_byte_buf => visit_byte_buf(Vec::new()),
+        deserialize_identifier => visit_str(""),
+    }
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.state.rejecting.insert(self.state.path.clone());
+        self.state.rejected = true;
+        self.leaf(Schema::Any);
+        visitor.visit_unit()
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.leaf(Schema::Any);
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.leaf(Schema::Unit);
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let mut inner = Schema::Any;
+        let value = match self.state.cut() {
+            true => visitor.visit_none(),
+            false => visitor.visit_some(Tracer {
+                slot: &mut inner,
+                state: self.state,
+            }),
+        };
+        *self.slot = Schema::Option(Box::new(inner));
+        value
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let mut element = Schema::Any;
+        let value = visitor.visit_seq(SeqTracer {
+            slots: match self.state.cut() {
+                true => Vec::new(),
+                false => vec![&mut element],
+            }
+            .into_iter()
+            .enumerate(),
+            state: self.state,
+        });
+        *self.slot = Schema::Seq(Box::new(element));
+        value
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        let mut elements = vec![Schema::Any; len];
+        let value = visitor.visit_seq(SeqTracer {
+            slots: elements.iter_mut().enumerate(),
+            state: self.state,
+        });
+        *self.slot = Schema::Tuple(elements);
+        value
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let mut value_schema = Schema::Any;
+        let value = visitor.visit_map(MapTracer {
+            entries: match self.state.cut() {
+                true => Vec::new(),
+                false => vec![(None, &mut value_schema)],
+            }
+            .into_iter(),
+            current: None,
+            state: self.state,
+        });
+        *self.slot = Schema::Map(Box::new(value_schema));
+        value
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        let recursive = self.state.structs.contains(&name);
+        self.state.structs.push(name);
+        self.state.recursion += usize::from(recursive);
+        let mut schemas = fields
+            .iter()
+            .map(|field| (*field, Schema::Any))
+            .collect::<IndexMap<_, _>>();
+        // a field whose placeholder gets rejected ends the run, it goes last
+        let last = fields
+            .iter()
+            .filter(|field| {
+                self.state
+                    .holds_rejecting(&self.state.path.join(Segment::Field((**field).into())))
+            })
+            .copied()
+            .collect::<Vec<_>>();
+        let value = visitor.visit_map(MapTracer {
+            entries: schemas
+                .iter_mut()
+                .map(|(field, schema)| (Some(*field), schema))
+                .collect::<Vec<_>>()
+                .tap_mut(|entries| {
+                    entries.sort_by_key(|(field, _)| field.is_some_and(|f| last.contains(&f)))
+                })
+                .into_iter(),
+            current: None,
+            state: self.state,
+        });
+        self.state.recursion -= usize::from(recursive);
+        self.state.structs.pop();
+        *self.slot = Schema::Struct(schemas);
+        value
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        let chosen = match self.state.cut() {
+            true => 0,
+            false => {
+                let next = self
+                    .state
+                    .variants
+                    .entry(self.state.path.clone())
+                    .or_default();
+                let chosen = (*next).min(variants.len().saturating_sub(1));
+                *next = chosen + 1;
+                self.state.pending |= *next < variants.len();
+                chosen
+            }
+        };
+        let mut schemas = variants
+            .iter()
+            .map(|variant| (*variant, Schema::Any))
+            .collect::<IndexMap<_, _>>();
+        let value = match schemas.get_index_mut(chosen) {
+            Some((variant, slot)) => visitor.visit_enum(EnumTracer {
+                variant,
+                slot,
+                state: self.state,
+            }),
+            None => Err(de::Error::custom("cannot trace an enum without variants")),
+        };
+        *self.slot = Schema::Enum(schemas);
+        value
+    }
+}
+
+struct SeqTracer<'t, I> {
+    slots: I,
+    state: &'t mut TraceState,
+}
+
+impl<'de, 's, I: Iterator<Item = (usize, &'s mut Schema)>> SeqAccess<'de> for SeqTracer<'_, I> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        match self.slots.next() {
+            Some((idx, slot)) => self
+                .state
+                .nested(Segment::Idx(idx), |state| {
+                    seed.deserialize(Tracer { slot, state })
+                })
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Struct fields (named keys) or a single map entry (traced key)
+struct MapTracer<'t, 's, I> {
+    entries: I,
+    current: Option<(Option<&'static str>, &'s mut Schema)>,
+    state: &'t mut TraceState,
+}
+
+impl<'de, 's, I> MapAccess<'de> for MapTracer<'_, 's, I>
+where
+    I: Iterator<Item = (Option<&'static str>, &'s mut Schema)>,
+{
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        self.current = self.entries.next();
+        match &self.current {
+            Some((Some(field), _)) => seed.deserialize(field.into_deserializer()).map(Some),
+            Some((None, _)) => seed
+                .deserialize(Tracer {
+                    slot: &mut Schema::Any,
+                    state: self.state,
+                })
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        let (field, slot) = self
+            .current
+            .take()
+            .ok_or_else(|| Error::Custom("next_value_seed called before next_key_seed".into()))?;
+        // map values don't have a name of their own, but still need a distinct path
+        self.state
+            .nested(Segment::Field(field.unwrap_or_default().into()), |state| {
+                seed.deserialize(Tracer { slot, state })
+            })
+    }
+}
+
+struct EnumTracer<'t, 's> {
+    variant: &'static str,
+    slot: &'s mut Schema,
+    state: &'t mut TraceState,
+}
+
+impl<'de, 't, 's> EnumAccess<'de> for EnumTracer<'t, 's> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self)> {
+        seed.deserialize(self.variant.into_deserializer())
+            .map(|variant| (variant, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for EnumTracer<'_, '_> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        *self.slot = Schema::Unit;
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        let Self {
+            variant,
+            slot,
+            state,
+        } = self;
+        state.nested(Segment::Field(variant.into()), |state| {
+            seed.deserialize(Tracer { slot, state })
+        })
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        let Self {
+            variant,
+            slot,
+            state,
+        } = self;
+        state.nested(Segment::Field(variant.into()), |state| {
+            Tracer { slot, state }.deserialize_tuple(len, visitor)
+        })
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        let Self {
+            variant,
+            slot,
+            state,
+        } = self;
+        state.nested(Segment::Field(variant.into()), |state| {
+            Tracer { slot, state }.deserialize_struct(variant, fields, visitor)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, serde::Deserialize, std::collections::BTreeMap};
+
+    fn headers_of<T: DeserializeOwned>() -> Vec<String> {
+        Schema::of::<T>()
+            .expect("tracing the schema")
+            .headers(&FlattenConfig::DEFAULT)
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Address {
+        city: String,
+        zip: Option<u32>,
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    enum Shape {
+        Empty,
+        Circle { radius: f64 },
+        Rect(u32, u32),
+        Named(String),
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Record {
+        name: String,
+        address: Option<Address>,
+        point: (i32, i32),
+        shape: Shape,
+        tags: Vec<String>,
+        extra: BTreeMap<String, u32>,
+    }
+
+    #[test]
+    fn test_headers_of_nested_type() {
+        assert_eq!(
+            headers_of::<Record>(),
+            vec![
+                "name",
+                "address__city",
+                "address__zip",
+                "point__idx-0",
+                "point__idx-1",
+                "shape",
+                "shape__Circle__radius",
+                "shape__Rect__idx-0",
+                "shape__Rect__idx-1",
+                "shape__Named",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dynamic_parts_are_recorded() {
+        let Schema::Struct(fields) = Schema::of::<Record>().unwrap() else {
+            panic!("expected a struct")
+        };
+        assert_eq!(fields["tags"], Schema::Seq(Box::new(Schema::Leaf)));
+        assert_eq!(fields["extra"], Schema::Map(Box::new(Schema::Leaf)));
+    }
+
+    #[test]
+    fn test_presence_marker_header() {
+        assert_eq!(
+            Schema::of::<Record>()
+                .unwrap()
+                .headers(&FlattenConfig::default().with_presence_marker("present"))[1..4],
+            ["address__present", "address__city", "address__zip"]
+        );
+    }
+
+    #[test]
+    fn test_covers() {
+        let schema = Schema::of::<Record>().unwrap();
+        let config = FlattenConfig::DEFAULT;
+        [
+            ("name", true),
+            ("address__city", true),
+            ("address__country", false),
+            ("point__idx-1", true),
+            ("point__idx-2", false),
+            ("shape", true),
+            ("shape__Circle__radius", true),
+            ("shape__Square__side", false),
+            ("tags__idx-7", true),
+            ("extra__anything", true),
+            ("name__first", false),
+            ("unknown", false),
+        ]
+        .into_iter()
+        .for_each(|(header, expected)| {
+            assert_eq!(
+                schema.covers(&FieldPath::parse(header, &config), &config),
+                expected,
+                "{header}"
+            )
+        });
+    }
+
+    #[test]
+    fn test_deserialize_any_parts_are_dynamic() {
+        #[derive(Deserialize)]
+        #[serde(tag = "type")]
+        #[allow(dead_code)]
+        enum Tagged {
+            Circle { radius: f64 },
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        #[allow(dead_code)]
+        enum Untagged {
+            Rect(u32, u32),
+        }
+
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        struct Inner {
+            count: u32,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        struct Flattened {
+            name: String,
+            #[serde(flatten)]
+            inner: Inner,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        struct Shapes {
+            tagged: Tagged,
+            id: u32,
+            untagged: Untagged,
+            address: Address,
+            flattened: Flattened,
+            tags: Vec<String>,
+        }
+
+        let schema = Schema::of::<Shapes>().expect("tracing the schema");
+        let config = FlattenConfig::DEFAULT;
+        // the fields after the dynamic ones are traced too
+        assert_eq!(
+            schema.headers(&config),
+            vec!["id", "address__city", "address__zip"]
+        );
+        assert_eq!(
+            schema.dynamic_paths(&config),
+            vec!["tagged", "untagged", "flattened", "tags"]
+        );
+        assert_eq!(
+            schema.dynamic_paths(&config.clone().with_array_width("tags", 2)),
+            vec!["tagged", "untagged", "flattened"]
+        );
+    }
+
+    #[test]
+    fn test_rejected_placeholders_are_traced() {
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        struct Host {
+            id: std::num::NonZeroU32,
+            address: Option<Address>,
+            ip: std::net::IpAddr,
+            name: String,
+        }
+
+        assert_eq!(
+            headers_of::<Host>(),
+            vec!["id", "address__city", "address__zip", "ip", "name"]
+        );
+    }
+
+    #[test]
+    fn test_rejected_values_name_their_path() {
+        #[allow(dead_code)]
+        struct Odd(u32);
+
+        impl<'de> serde::Deserialize<'de> for Odd {
+            fn deserialize<D: Deserializer<'de>>(
+                deserializer: D,
+            ) -> std::result::Result<Self, D::Error> {
+                u32::deserialize(deserializer).and_then(|value| match value % 2 {
+                    1 => Ok(Odd(value)),
+                    _ => Err(de::Error::custom("expected an odd number")),
+                })
+            }
+        }
+
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        struct Counts {
+            name: String,
+            inner: Option<Vec<Odd>>,
+        }
+
+        assert!(matches!(
+            Schema::of::<Counts>(),
+            Err(Error::Rejected { path, message })
+                if path == "inner__idx-0" && message == "expected an odd number"
+        ));
+    }
+
+    #[test]
+    fn test_recursive_type_terminates() {
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        struct Tree {
+            value: u8,
+            left: Option<Box<Tree>>,
+        }
+
+        assert_eq!(headers_of::<Tree>(), vec!["value", "left__value"]);
+    }
+}
@@ -0,0 +1,1058 @@
+//! [`serde::Serializer`] which flattens a value on the fly.
+//!
+//! Instead of going through `serde_json::to_value` and walking the resulting tree,
+//! every leaf is sent straight into a [`FlatSink`] together with its [`FieldPath`]
+//! and its already rendered flat key (e.g. `user__address__city`).
+
+use {
+    super::{FieldPath, FlattenConfig, Segment, flatten::Error},
+    indexmap::{IndexMap, map::Entry as IndexEntry},
+    serde::{
+        Serialize, Serializer,
+        ser::{self, Error as _},
+    },
+    serde_json::{Value, map::Entry},
+    std::{borrow::Cow, fmt::Write},
+    tap::{Pipe, Tap},
+};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Name of the struct `serde_json::Number` serializes as with its `arbitrary_precision`
+/// feature, holding the exact text of the number
+pub const NUMBER_TOKEN: &str = "$serde_json::private::Number";
+
+/// A single scalar value produced by flattening
+#[derive(Debug, Clone, PartialEq)]
+pub enum Leaf<'a> {
+    Null,
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    I128(i128),
+    U128(u128),
+    F32(f32),
+    F64(f64),
+    /// exact text of a number, see [`NUMBER_TOKEN`]
+    Number(Cow<'a, str>),
+    Str(Cow<'a, str>),
+}
+
+impl Leaf<'_> {
+    pub fn into_owned(self) -> Leaf<'static> {
+        match self {
+            Leaf::Null => Leaf::Null,
+            Leaf::Bool(v) => Leaf::Bool(v),
+            Leaf::I64(v) => Leaf::I64(v),
+            Leaf::U64(v) => Leaf::U64(v),
+            Leaf::I128(v) => Leaf::I128(v),
+            Leaf::U128(v) => Leaf::U128(v),
+            Leaf::F32(v) => Leaf::F32(v),
+            Leaf::F64(v) => Leaf::F64(v),
+            Leaf::Number(v) => Leaf::Number(Cow::Owned(v.into_owned())),
+            Leaf::Str(v) => Leaf::Str(Cow::Owned(v.into_owned())),
+        }
+    }
+
+    /// Same representation `serde_json::to_value` would produce, except that 128-bit
+    /// integers too large for a `serde_json::Number` become strings instead of an error
+    pub fn to_value(&self) -> Value {
+        match self {
+            Leaf::Null => Value::Null,
+            Leaf::Bool(v) => Value::Bool(*v),
+            Leaf::I64(v) => Value::from(*v),
+            Leaf::U64(v) => Value::from(*v),
+            Leaf::I128(v) => serde_json::Number::from_i128(*v)
+                .map(Value::Number)
+                .unwrap_or_else(|| Value::String(v.to_string())),
+            Leaf::U128(v) => serde_json::Number::from_u128(*v)
+                .map(Value::Number)
+                .unwrap_or_else(|| Value::String(v.to_string())),
+            Leaf::F32(v) => Value::from(*v),
+            Leaf::F64(v) => Value::from(*v),
+            Leaf::Number(v) => v
+                .parse()
+                .map(Value::Number)
+                .unwrap_or_else(|_| Value::String(v.to_string())),
+            Leaf::Str(v) => Value::String(v.to_string()),
+        }
+    }
+
+    /// Appends the text of a csv cell for this leaf
+    pub fn write_cell(&self, out: &mut String, config: &FlattenConfig) {
+        match self {
+            Leaf::Null => out.push_str(config.null_cell()),
+            Leaf::Bool(v) => out.push_str(if *v { "true" } else { "false" }),
+            Leaf::I64(v) => write!(out, "{v}").expect("writing to string"),
+            Leaf::U64(v) => write!(out, "{v}").expect("writing to string"),
+            Leaf::I128(v) => write!(out, "{v}").expect("writing to string"),
+            Leaf::U128(v) => write!(out, "{v}").expect("writing to string"),
+            Leaf::F32(v) if !v.is_finite() => {
+                out.push_str(config.non_finite.token(f64::from(*v)).unwrap_or_default())
+            }
+            Leaf::F64(v) if !v.is_finite() => {
+                out.push_str(config.non_finite.token(*v).unwrap_or_default())
+            }
+            // serde_json's formatting is kept, so e.g. `1.0` stays `1.0`
+            Leaf::F32(_) | Leaf::F64(_) => {
+                if let Value::Number(number) = self.to_value() {
+                    write!(out, "{number}").expect("writing to string")
+                }
+            }
+            Leaf::Number(v) | Leaf::Str(v) => out.push_str(v),
+        }
+    }
+}
+
+impl Serialize for Leaf<'_> {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Leaf::Null => serializer.serialize_unit(),
+            Leaf::Bool(v) => serializer.serialize_bool(*v),
+            Leaf::I64(v) => serializer.serialize_i64(*v),
+            Leaf::U64(v) => serializer.serialize_u64(*v),
+            Leaf::I128(v) => serializer.serialize_i128(*v),
+            Leaf::U128(v) => serializer.serialize_u128(*v),
+            Leaf::F32(v) => serializer.serialize_f32(*v),
+            Leaf::F64(v) => serializer.serialize_f64(*v),
+            Leaf::Number(v) => match v.parse::<serde_json::Number>() {
+                Ok(number) => number.serialize(serializer),
+                Err(_) => serializer.serialize_str(v),
+            },
+            Leaf::Str(v) => serializer.serialize_str(v),
+        }
+    }
+}
+
+/// Receives the leaves produced by [`FlatSerializer`]
+pub trait FlatSink {
+    /// `key` is the `path` rendered using `config`
+    fn push(
+        &mut self,
+        path: &FieldPath<'_>,
+        key: &str,
+        leaf: Leaf<'_>,
+        config: &FlattenConfig,
+    ) -> Result<()>;
+}
+
+impl<T: FlatSink + ?Sized> FlatSink for &mut T {
+    fn push(
+        &mut self,
+        path: &FieldPath<'_>,
+        key: &str,
+        leaf: Leaf<'_>,
+        config: &FlattenConfig,
+    ) -> Result<()> {
+        (**self).push(path, key, leaf, config)
+    }
+}
+
+impl FlatSink for serde_json::Map<String, Value> {
+    fn push(
+        &mut self,
+        _: &FieldPath<'_>,
+        key: &str,
+        leaf: Leaf<'_>,
+        _: &FlattenConfig,
+    ) -> Result<()> {
+        match self.entry(key) {
+            Entry::Vacant(entry) => entry.insert(leaf.to_value()).pipe(drop).pipe(Ok),
+            Entry::Occupied(entry) => Err(Error::KeyCollision {
+                key: entry.key().clone(),
+            }),
+        }
+    }
+}
+
+impl FlatSink for IndexMap<String, Leaf<'static>> {
+    fn push(
+        &mut self,
+        _: &FieldPath<'_>,
+        key: &str,
+        leaf: Leaf<'_>,
+        _: &FlattenConfig,
+    ) -> Result<()> {
+        match self.entry(key.to_string()) {
+            IndexEntry::Vacant(entry) => entry.insert(leaf.into_owned()).pipe(drop).pipe(Ok),
+            IndexEntry::Occupied(entry) => Err(Error::KeyCollision {
+                key: entry.key().clone(),
+            }),
+        }
+    }
+}
+
+/// Flattens anything implementing [`Serialize`] straight into a [`FlatSink`]
+pub struct FlatSerializer<'c, S> {
+    config: &'c FlattenConfig,
+    sink: S,
+    path: FieldPath<'static>,
+    key: String,
+    key_lens: Vec<usize>,
+    /// path length of an `Option` whose presence marker is still to be written,
+    /// see [`FlattenConfig::presence_marker`]
+    presence: Option<usize>,
+}
+
+impl<'c, S: FlatSink> FlatSerializer<'c, S> {
+    pub fn new(config: &'c FlattenConfig, sink: S) -> Self {
+        Self {
+            config,
+            sink,
+            path: Default::default(),
+            key: String::new(),
+            key_lens: Vec::new(),
+            presence: None,
+        }
+    }
+
+    pub fn into_sink(self) -> S {
+        self.sink
+    }
+
+    fn push_segment(&mut self, segment: Segment<'static>) {
+        self.key_lens.push(self.key.len());
+        if !self.path.0.is_empty() {
+            self.key.push_str(&self.config.separator);
+        }
+        match &segment {
+            Segment::Idx(_) => self.key.push_str(&segment.render(self.config)),
+            Segment::Field(field) => self.key.push_str(&self.config.escape_field(field)),
+        }
+        self.path.0.push(segment);
+    }
+
+    fn pop_segment(&mut self) {
+        self.path.0.pop();
+        if let Some(len) = self.key_lens.pop() {
+            self.key.truncate(len);
+        }
+    }
+
+    fn leaf(&mut self, leaf: Leaf<'_>) -> Result<()> {
+        self.presence = None;
+        self.sink.push(&self.path, &self.key, leaf, self.config)
+    }
+
+    /// Called when a container starts, writes the presence marker of the `Option` holding it
+    fn container(&mut self) -> Result<()> {
+        match (self.presence.take(), &self.config.presence_marker) {
+            (Some(len), Some(marker)) if len == self.path.0.len() => {
+                self.push_segment(Segment::Field(marker.clone()));
+                let result = self.leaf(Leaf::Bool(true));
+                self.pop_segment();
+                result
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn nested<T: Serialize + ?Sized>(
+        &mut self,
+        segment: Segment<'static>,
+        value: &T,
+    ) -> Result<()> {
+        self.push_segment(segment);
+        let result = match self.config.is_opaque(&self.path) {
+            true => self.opaque(value),
+            false => value.serialize(&mut *self),
+        };
+        self.pop_segment();
+        result
+    }
+
+    /// objects and arrays become a single cell of compact JSON, see [`FlattenConfig::is_opaque`]
+    fn opaque<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        serde_json::to_string(value)
+            .map_err(|e| Error::Custom(e.to_string()))
+            .and_then(|json| match json.starts_with(['[', '{']) {
+                true => self.leaf(Leaf::Str(Cow::Owned(json))),
+                false => value.serialize(&mut *self),
+            })
+    }
+}
+
+impl<'a, 'c, S: FlatSink> Serializer for &'a mut FlatSerializer<'c, S> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Compound<'a, 'c, S>;
+    type SerializeTuple = Compound<'a, 'c, S>;
+    type SerializeTupleStruct = Compound<'a, 'c, S>;
+    type SerializeTupleVariant = Compound<'a, 'c, S>;
+    type SerializeMap = Compound<'a, 'c, S>;
+    type SerializeStruct = Compound<'a, 'c, S>;
+    type SerializeStructVariant = Compound<'a, 'c, S>;
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.leaf(Leaf::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.leaf(Leaf::I64(v.into()))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.leaf(Leaf::I64(v.into()))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.leaf(Leaf::I64(v.into()))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        self.leaf(Leaf::I64(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.leaf(Leaf::U64(v.into()))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.leaf(Leaf::U64(v.into()))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.leaf(Leaf::U64(v.into()))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        self.leaf(Leaf::U64(v))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<()> {
+        self.leaf(Leaf::I128(v))
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<()> {
+        self.leaf(Leaf::U128(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        self.leaf(Leaf::F32(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        self.leaf(Leaf::F64(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.leaf(Leaf::Str(Cow::Owned(v.to_string())))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        self.leaf(Leaf::Str(Cow::Borrowed(v)))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        match self.config.bytes.encode(v) {
+            Some(cell) => self.leaf(Leaf::Str(Cow::Owned(cell))),
+            // same as serde_json: bytes are an array of numbers
+            None => v
+                .iter()
+                .enumerate()
+                .try_for_each(|(idx, byte)| self.nested(Segment::Idx(idx), byte)),
+        }
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        self.leaf(Leaf::Null)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        if self.config.presence_marker.is_some() && !self.path.0.is_empty() {
+            self.presence = Some(self.path.0.len());
+        }
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        self.leaf(Leaf::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        self.leaf(Leaf::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<()> {
+        self.leaf(Leaf::Str(Cow::Borrowed(variant)))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.nested(Segment::Field(Cow::Borrowed(variant)), value)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        self.container()?;
+        let width = self.config.array_width(&self.path);
+        let cells = self.config.multi_value(&self.path).map(|_| Vec::new());
+        Ok(Compound::new(self)
+            .array()
+            .with_width(width)
+            .with_cells(cells))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        self.container()?;
+        Ok(Compound::new(self).array())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        self.container()?;
+        Ok(Compound::new(self).array())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        self.push_segment(Segment::Field(Cow::Borrowed(variant)));
+        Ok(Compound::new(self).array().in_variant())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        self.container()?;
+        Ok(Compound::new(self))
+    }
+
+    fn serialize_struct(self, name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        if name != NUMBER_TOKEN {
+            self.container()?;
+        }
+        Ok(Compound::new(self).with_raw_number(name == NUMBER_TOKEN))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        self.push_segment(Segment::Field(Cow::Borrowed(variant)));
+        Ok(Compound::new(self).in_variant())
+    }
+}
+
+/// Catches a value serialized as a single leaf, anything nested makes it not a scalar
+#[derive(Default)]
+struct ScalarSink {
+    leaf: Option<Leaf<'static>>,
+    nested: bool,
+}
+
+impl FlatSink for ScalarSink {
+    fn push(
+        &mut self,
+        path: &FieldPath<'_>,
+        _: &str,
+        leaf: Leaf<'_>,
+        _: &FlattenConfig,
+    ) -> Result<()> {
+        match (path.0.is_empty(), self.leaf.is_none()) {
+            (true, true) => self.leaf = Some(leaf.into_owned()),
+            _ => self.nested = true,
+        }
+        Ok(())
+    }
+}
+
+/// State shared by all the compound serializers
+pub struct Compound<'a, 'c, S> {
+    ser: &'a mut FlatSerializer<'c, S>,
+    next_idx: usize,
+    next_key: Option<String>,
+    in_variant: bool,
+    /// see [`FlattenConfig::array_widths`]
+    width: Option<usize>,
+    /// scalar elements collected into one cell, see [`FlattenConfig::multi_value`]
+    cells: Option<Vec<Leaf<'static>>>,
+    /// written when nothing else was, see [`FlattenConfig::empty_markers`]
+    empty_marker: &'static str,
+    /// a `serde_json::Number` holding its exact text, see [`NUMBER_TOKEN`]
+    raw_number: bool,
+}
+
+impl<'a, 'c, S: FlatSink> Compound<'a, 'c, S> {
+    fn new(ser: &'a mut FlatSerializer<'c, S>) -> Self {
+        Self {
+            ser,
+            next_idx: 0,
+            next_key: None,
+            in_variant: false,
+            width: None,
+            cells: None,
+            empty_marker: "{}",
+            raw_number: false,
+        }
+    }
+
+    fn array(self) -> Self {
+        Self {
+            empty_marker: "[]",
+            ..self
+        }
+    }
+
+    fn with_width(self, width: Option<usize>) -> Self {
+        Self { width, ..self }
+    }
+
+    fn with_cells(self, cells: Option<Vec<Leaf<'static>>>) -> Self {
+        Self { cells, ..self }
+    }
+
+    fn with_raw_number(self, raw_number: bool) -> Self {
+        Self { raw_number, ..self }
+    }
+
+    /// collects a scalar into [`Compound::cells`], the first non-scalar element turns
+    /// the already collected ones into regular elements
+    fn cell<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let mut scalar = FlatSerializer::new(self.ser.config, ScalarSink::default());
+        value.serialize(&mut scalar)?;
+        match (scalar.into_sink(), self.cells.as_mut()) {
+            (
+                ScalarSink {
+                    leaf: Some(leaf),
+                    nested: false,
+                },
+                Some(cells),
+            ) => {
+                self.next_idx += 1;
+                cells.push(leaf);
+                Ok(())
+            }
+            _ => self
+                .cells
+                .take()
+                .unwrap_or_default()
+                .into_iter()
+                .enumerate()
+                .try_for_each(|(idx, leaf)| {
+                    self.ser.push_segment(Segment::Idx(idx));
+                    let result = self.ser.leaf(leaf);
+                    self.ser.pop_segment();
+                    result
+                })
+                .and_then(|()| self.element(value)),
+        }
+    }
+
+    /// the variant segment was pushed and has to be popped when done
+    fn in_variant(self) -> Self {
+        Self {
+            in_variant: true,
+            ..self
+        }
+    }
+
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let idx = self.next_idx;
+        self.next_idx += 1;
+        self.ser.nested(Segment::Idx(idx), value)
+    }
+
+    fn finish(self) -> Result<()> {
+        if self.next_idx == 0
+            && self.width.unwrap_or_default() == 0
+            && self.cells.is_none()
+            && self.ser.config.empty_markers
+            && !self.ser.path.0.is_empty()
+        {
+            self.ser.leaf(Leaf::Str(Cow::Borrowed(self.empty_marker)))?;
+        }
+        if self.in_variant {
+            self.ser.pop_segment();
+        }
+        Ok(())
+    }
+}
+
+impl<S: FlatSink> ser::SerializeSeq for Compound<'_, '_, S> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        match self.width {
+            Some(width) if self.next_idx >= width => Err(Error::ArrayTooLong {
+                key: self.ser.key.clone(),
+                width,
+            }),
+            _ if self.cells.is_some() => self.cell(value),
+            _ => self.element(value),
+        }
+    }
+
+    fn end(self) -> Result<()> {
+        match (
+            self.cells.as_ref(),
+            self.ser.config.multi_value(&self.ser.path),
+        ) {
+            (Some(cells), Some(multi_value)) => cells
+                .iter()
+                .map(|leaf| String::new().tap_mut(|cell| leaf.write_cell(cell, self.ser.config)))
+                .collect::<Vec<_>>()
+                .pipe(|cells| multi_value.join(cells.iter().map(String::as_str)))
+                .pipe(|cell| self.ser.leaf(Leaf::Str(Cow::Owned(cell))))?,
+            _ => (self.next_idx..self.width.unwrap_or_default()).try_for_each(|idx| {
+                self.ser.push_segment(Segment::Idx(idx));
+                let result = self.ser.leaf(Leaf::Null);
+                self.ser.pop_segment();
+                result
+            })?,
+        }
+        self.finish()
+    }
+}
+
+impl<S: FlatSink> ser::SerializeTuple for Compound<'_, '_, S> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<S: FlatSink> ser::SerializeTupleStruct for Compound<'_, '_, S> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<S: FlatSink> ser::SerializeTupleVariant for Compound<'_, '_, S> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<S: FlatSink> ser::SerializeMap for Compound<'_, '_, S> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.next_key = Some(key.serialize(KeySerializer {
+            delimiter: self.ser.config.key_delimiter,
+        })?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.next_key
+            .take()
+            .ok_or_else(|| Error::custom("serialize_value called before serialize_key"))
+            .and_then(|key| {
+                self.next_idx += 1;
+                self.ser.nested(Segment::Field(Cow::Owned(key)), value)
+            })
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<S: FlatSink> ser::SerializeStruct for Compound<'_, '_, S> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.next_idx += 1;
+        match self.raw_number && key == NUMBER_TOKEN {
+            true => value
+                .serialize(KeySerializer {
+                    delimiter: self.ser.config.key_delimiter,
+                })
+                .and_then(|text| self.ser.leaf(Leaf::Number(Cow::Owned(text)))),
+            false => self.ser.nested(Segment::Field(Cow::Borrowed(key)), value),
+        }
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<S: FlatSink> ser::SerializeStructVariant for Compound<'_, '_, S> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.next_idx += 1;
+        self.ser.nested(Segment::Field(Cow::Borrowed(key)), value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+/// Turns map keys into a single path segment, same rules as serde_json except that
+/// tuples are allowed, see [`FlattenConfig::key_delimiter`]
+struct KeySerializer {
+    delimiter: char,
+}
+
+impl Serializer for KeySerializer {
+    type Ok = String;
+    type Error = Error;
+    type SerializeSeq = ser::Impossible<String, Error>;
+    type SerializeTuple = KeyTuple;
+    type SerializeTupleStruct = KeyTuple;
+    type SerializeTupleVariant = ser::Impossible<String, Error>;
+    type SerializeMap = ser::Impossible<String, Error>;
+    type SerializeStruct = ser::Impossible<String, Error>;
+    type SerializeStructVariant = ser::Impossible<String, Error>;
+
+    fn serialize_bool(self, v: bool) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_char(self, v: char) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<String> {
+        Err(Error::KeyMustBeAString)
+    }
+
+    fn serialize_none(self) -> Result<String> {
+        Err(Error::KeyMustBeAString)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, _value: &T) -> Result<String> {
+        Err(Error::KeyMustBeAString)
+    }
+
+    fn serialize_unit(self) -> Result<String> {
+        Err(Error::KeyMustBeAString)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<String> {
+        Err(Error::KeyMustBeAString)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<String> {
+        Ok(variant.to_string())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<String> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<String> {
+        Err(Error::KeyMustBeAString)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Err(Error::KeyMustBeAString)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple> {
+        Ok(KeyTuple {
+            delimiter: self.delimiter,
+            parts: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        self.serialize_tuple(len)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Err(Error::KeyMustBeAString)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Err(Error::KeyMustBeAString)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        Err(Error::KeyMustBeAString)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Err(Error::KeyMustBeAString)
+    }
+}
+
+/// Elements of a tuple key, joined like the cells of a [`MultiValue`](super::MultiValue)
+struct KeyTuple {
+    delimiter: char,
+    parts: Vec<String>,
+}
+
+impl ser::SerializeTuple for KeyTuple {
+    type Ok = String;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value
+            .serialize(KeySerializer {
+                delimiter: self.delimiter,
+            })
+            .map(|part| self.parts.push(part))
+    }
+
+    fn end(self) -> Result<String> {
+        Ok(super::MultiValue::new(self.delimiter).join(self.parts.iter().map(String::as_str)))
+    }
+}
+
+impl ser::SerializeTupleStruct for KeyTuple {
+    type Ok = String;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeTuple::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<String> {
+        ser::SerializeTuple::end(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, serde_json::json};
+
+    #[derive(Serialize)]
+    enum Shape {
+        Circle { radius: f64 },
+        Square(u32),
+        Empty,
+    }
+
+    #[derive(Serialize)]
+    struct Record {
+        name: &'static str,
+        shapes: Vec<Shape>,
+        scores: std::collections::BTreeMap<u32, Option<i8>>,
+        pair: (bool, char),
+    }
+
+    #[test]
+    fn test_matches_flattening_a_value() {
+        let record = Record {
+            name: "x",
+            shapes: vec![
+                Shape::Circle { radius: 1.0 },
+                Shape::Square(2),
+                Shape::Empty,
+            ],
+            scores: [(1, Some(-1)), (2, None)].into(),
+            pair: (true, 'c'),
+        };
+
+        let direct = FlatSerializer::new(&FlattenConfig::DEFAULT, serde_json::Map::new())
+            .pipe(|mut ser| record.serialize(&mut ser).map(|()| ser.into_sink()))
+            .unwrap();
+        let through_value = serde_json::to_value(&record)
+            .map(|value| {
+                crate::flatten_json_value::flatten::flattened_iter(Default::default(), value)
+                    .map(|(path, value)| (path.render(&FlattenConfig::DEFAULT), value))
+                    .collect::<serde_json::Map<_, _>>()
+            })
+            .unwrap();
+        assert_eq!(direct, through_value);
+        assert_eq!(
+            direct.get("shapes__idx-0__Circle__radius"),
+            Some(&json!(1.0))
+        );
+        assert_eq!(direct.get("scores__2"), Some(&json!(null)));
+    }
+
+    /// Cells are appended in the order the leaves are produced, the keys are ignored
+    impl FlatSink for csv::StringRecord {
+        fn push(
+            &mut self,
+            _: &FieldPath<'_>,
+            _: &str,
+            leaf: Leaf<'_>,
+            config: &FlattenConfig,
+        ) -> Result<()> {
+            match leaf {
+                Leaf::Str(v) => self.push_field(&v),
+                other => self.push_field(&String::new().pipe(|mut cell| {
+                    other.write_cell(&mut cell, config);
+                    cell
+                })),
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_string_record_sink() {
+        let mut record = csv::StringRecord::new();
+        (1u8, None::<u8>, "a", 0.5f32)
+            .serialize(&mut FlatSerializer::new(
+                &FlattenConfig::DEFAULT,
+                &mut record,
+            ))
+            .unwrap();
+        assert_eq!(record, csv::StringRecord::from(vec!["1", "", "a", "0.5"]));
+    }
+
+    #[test]
+    fn test_collision_is_an_error() {
+        #[derive(Serialize)]
+        struct Inner {
+            a: u8,
+        }
+        #[derive(Serialize)]
+        struct Outer {
+            a: u8,
+            #[serde(flatten)]
+            inner: Inner,
+        }
+
+        let result = Outer {
+            a: 1,
+            inner: Inner { a: 2 },
+        }
+        .serialize(&mut FlatSerializer::new(
+            &FlattenConfig::DEFAULT,
+            serde_json::Map::new(),
+        ));
+        assert!(matches!(result, Err(Error::KeyCollision { key }) if key == "a"));
+    }
+}
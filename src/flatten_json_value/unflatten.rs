@@ -16,7 +16,7 @@ pub enum Error {
 
 type Result<T> = std::result::Result<T, self::Error>;
 
-use crate::flatten_json_value::{FieldPath, JOIN_TAG, Segment};
+use crate::flatten_json_value::{FieldPath, FlattenConfig, Segment};
 
 trait TryFlatMapExt<'a, T, E> {
     fn try_flat_map<U, F, OutIter>(
@@ -52,8 +52,11 @@ where
     }
 }
 
-#[instrument]
-pub fn unflatten_iter(value: Value) -> impl Iterator<Item = Result<(FieldPath<'static>, Value)>> {
+#[instrument(skip(config))]
+pub fn unflatten_iter(
+    value: Value,
+    config: &FlattenConfig,
+) -> impl Iterator<Item = Result<(FieldPath<'static>, Value)>> + '_ {
     match value {
         Value::Object(map) => Ok(map),
         other => {
@@ -69,15 +72,26 @@ pub fn unflatten_iter(value: Value) -> impl Iterator<Item = Result<(FieldPath<'s
         }
     }
     .pipe(once)
-    .try_flat_map(|values| {
-        values.into_iter().map(|(key, value)| match value {
-            value @ (Value::Null | Value::Bool(_) | Value::Number(_) | Value::String(_)) => key
-                .split(JOIN_TAG)
-                .map(Segment::from_str)
-                .collect::<Vec<_>>()
-                .pipe(FieldPath)
-                .pipe(|key| (key.to_owned(), value))
-                .pipe(Ok),
+    .try_flat_map(move |values| {
+        values.into_iter().map(move |(key, value)| match value {
+            // see FlattenConfig::empty_markers
+            Value::String(marker)
+                if config.empty_markers && matches!(marker.as_str(), "[]" | "{}") =>
+            {
+                FieldPath::parse(&key, config)
+                    .to_owned()
+                    .pipe(|key| match marker.as_str() {
+                        "[]" => (key, Value::Array(Vec::new())),
+                        _ => (key, Value::Object(Default::default())),
+                    })
+                    .pipe(Ok)
+            }
+            value @ (Value::Null | Value::Bool(_) | Value::Number(_) | Value::String(_)) => {
+                FieldPath::parse(&key, config)
+                    .to_owned()
+                    .pipe(|key| (key, value))
+                    .pipe(Ok)
+            }
             _other => Err(self::Error::UnsupportedChildValue { key: key.clone() }),
         })
     })
@@ -198,10 +212,17 @@ impl ValueBuilder<'_> {
     }
 }
 
-#[instrument]
 pub fn unflattened(value: serde_json::Value) -> Result<serde_json::Value> {
+    unflattened_with_config(value, &FlattenConfig::DEFAULT)
+}
+
+#[instrument(skip(config))]
+pub fn unflattened_with_config(
+    value: serde_json::Value,
+    config: &FlattenConfig,
+) -> Result<serde_json::Value> {
     let mut out = serde_json::Value::Null;
-    unflatten_iter(value)
+    unflatten_iter(value, config)
         .try_fold(ValueBuilder(&mut out), |mut out, next| {
             next.and_then(|(key, value)| {
                 out.apply(key, value)
@@ -231,6 +252,7 @@ pub mod test {
         })
         .pipe(|expected| {
             crate::flatten_json_value::flatten::flattened(expected.clone())
+                .context("flattening")?
                 .pipe(serde_json::Value::from)
                 .pipe(super::unflattened)
                 .context("unflattening")
@@ -1,2 +1,4 @@
+pub mod headers;
+pub mod leftovers;
 pub mod read;
 pub mod write;
@@ -0,0 +1,160 @@
+//! Mapping between the flattened paths of a type and the headers of a file.
+//!
+//! Sits in front of the reader's header tree and the writer's header line, so exports
+//! whose column names drifted between versions still line up with the type.
+
+use {
+    crate::flatten_json_value::{FieldPath, FlattenConfig, schema::Schema},
+    itertools::Itertools,
+    std::collections::BTreeMap,
+    tap::Tap,
+};
+
+/// How headers are compared with field names, aliases and labels
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HeaderMatching {
+    #[default]
+    Exact,
+    /// `Child_1__Field_2` matches `child_1__field_2`
+    CaseInsensitive,
+    /// case-insensitive, ignoring `_`, `-` and spaces as well, so `postal-code` matches
+    /// `PostalCode`. The path separator still has to be there.
+    Loose,
+}
+
+impl HeaderMatching {
+    pub fn matches(&self, header: &str, name: &str) -> bool {
+        let loose = |text: &str| {
+            text.chars()
+                .filter(|c| !matches!(c, '_' | '-' | ' '))
+                .flat_map(char::to_lowercase)
+                .collect::<String>()
+        };
+        match self {
+            HeaderMatching::Exact => header == name,
+            HeaderMatching::CaseInsensitive => header.to_lowercase() == name.to_lowercase(),
+            HeaderMatching::Loose => loose(header) == loose(name),
+        }
+    }
+}
+
+/// Renames, aliases and display labels of the columns.
+///
+/// Paths are flattened paths of the type (`customer__zip`), headers are what the file holds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderMapping {
+    /// path prefix => header prefix, e.g. `customer` => `client` writes `client__zip` and
+    /// reads it back as `customer__zip`. Only whole segments are renamed.
+    pub renames: Vec<(String, String)>,
+    /// extra headers read as a path, e.g. `customer__postal_code` for `customer__zip`
+    pub aliases: BTreeMap<String, String>,
+    /// path => header written instead of it, e.g. `Postal code`, read back as the path
+    pub labels: BTreeMap<String, String>,
+    pub matching: HeaderMatching,
+}
+
+impl HeaderMapping {
+    /// see [`HeaderMapping::renames`]
+    pub fn with_rename(
+        self,
+        path_prefix: impl Into<String>,
+        header_prefix: impl Into<String>,
+    ) -> Self {
+        self.tap_mut(|mapping| {
+            mapping
+                .renames
+                .push((path_prefix.into(), header_prefix.into()))
+        })
+    }
+
+    /// see [`HeaderMapping::aliases`]
+    pub fn with_alias(self, path: impl Into<String>, alias: impl Into<String>) -> Self {
+        self.tap_mut(|mapping| {
+            mapping.aliases.insert(alias.into(), path.into());
+        })
+    }
+
+    /// see [`HeaderMapping::labels`]
+    pub fn with_label(self, path: impl Into<String>, label: impl Into<String>) -> Self {
+        self.tap_mut(|mapping| {
+            mapping.labels.insert(path.into(), label.into());
+        })
+    }
+
+    /// see [`HeaderMapping::matching`]
+    pub fn with_matching(self, matching: HeaderMatching) -> Self {
+        Self { matching, ..self }
+    }
+
+    /// Header written for the flattened `path`
+    pub fn header(&self, path: &str, config: &FlattenConfig) -> String {
+        self.labels.get(path).cloned().unwrap_or_else(|| {
+            self.renames
+                .iter()
+                .find_map(|(path_prefix, header_prefix)| {
+                    replace_prefix(
+                        path,
+                        path_prefix,
+                        header_prefix,
+                        HeaderMatching::Exact,
+                        config,
+                    )
+                })
+                .unwrap_or_else(|| path.to_string())
+        })
+    }
+
+    /// Flattened path a header is read as. Field names which only match the ones of the
+    /// type with a non-exact [`HeaderMatching`] are resolved through its `schema`.
+    pub fn path(&self, header: &str, schema: Option<&Schema>, config: &FlattenConfig) -> String {
+        let matches = |name: &str| self.matching.matches(header, name);
+        self.labels
+            .iter()
+            .find(|(_, label)| matches(label))
+            .map(|(path, _)| path.clone())
+            .or_else(|| {
+                self.aliases
+                    .iter()
+                    .find(|(alias, _)| matches(alias))
+                    .map(|(_, path)| path.clone())
+            })
+            .unwrap_or_else(|| {
+                let renamed = self
+                    .renames
+                    .iter()
+                    .find_map(|(path_prefix, header_prefix)| {
+                        replace_prefix(header, header_prefix, path_prefix, self.matching, config)
+                    })
+                    .unwrap_or_else(|| header.to_string());
+                match (self.matching, schema) {
+                    (HeaderMatching::Exact, _) | (_, None) => renamed,
+                    (matching, Some(schema)) => schema
+                        .resolve(&FieldPath::parse(&renamed, config), &|header, name| {
+                            matching.matches(header, name)
+                        })
+                        .render(config),
+                }
+            })
+    }
+}
+
+/// `key` with its leading segments `from` replaced by `to`, if it starts with them
+fn replace_prefix(
+    key: &str,
+    from: &str,
+    to: &str,
+    matching: HeaderMatching,
+    config: &FlattenConfig,
+) -> Option<String> {
+    let (key, from) = (config.split(key), config.split(from));
+    (key.len() >= from.len()
+        && key
+            .iter()
+            .zip(&from)
+            .all(|(segment, from)| matching.matches(segment, from)))
+    .then(|| {
+        std::iter::once(to)
+            .chain(key[from.len()..].iter().copied())
+            .join(config.separator.as_ref())
+    })
+}
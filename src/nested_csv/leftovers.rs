@@ -0,0 +1,147 @@
+//! Helpers for `#[serde(flatten)]` fields of nested csv records.
+//!
+//! Serde buffers everything that reaches a flattened field, so the reader only sees
+//! `deserialize_any` and has to guess the type of every cell (see
+//! [`GuessTypes`](crate::flatten_json_value::GuessTypes)). A guess the field's type rejects
+//! is read again as text, see
+//! [`FlattenConfig::text_retries`](crate::flatten_json_value::FlattenConfig::text_retries).
+
+use {
+    crate::{
+        flatten_json_value::{FieldPath, FlattenConfig, Segment, flatten::flattened_iter},
+        serde::flattened_map_deserializer::{guessed_text, map_path},
+    },
+    indexmap::IndexMap,
+    serde::{Deserialize, Deserializer, Serialize, Serializer},
+    serde_json::Value,
+    std::borrow::Cow,
+    tap::Pipe,
+};
+
+/// Catch-all for the columns no other field asked for:
+///
+/// ```ignore
+/// #[derive(Deserialize)]
+/// struct Record {
+///     id: u32,
+///     #[serde(flatten)]
+///     extra: Leftovers,
+/// }
+/// ```
+///
+/// Serializing it writes the same columns back. Cells read as numbers or booleans keep their
+/// text (`1.50` stays `1.50`, not `1.5`) unless their value gets changed.
+#[derive(Debug, Clone, Default)]
+pub struct Leftovers {
+    pub cells: serde_json::Map<String, Value>,
+    /// text of the cells read as something else than a string, with the value they were read
+    /// as, by path below the field
+    texts: IndexMap<FieldPath<'static>, (Value, String)>,
+    /// rendered path of the struct holding the field, empty unless read from a nested csv
+    prefix: String,
+}
+
+impl PartialEq for Leftovers {
+    fn eq(&self, other: &Self) -> bool {
+        self.cells == other.cells
+    }
+}
+
+impl From<serde_json::Map<String, Value>> for Leftovers {
+    fn from(cells: serde_json::Map<String, Value>) -> Self {
+        Self {
+            cells,
+            texts: IndexMap::new(),
+            prefix: String::new(),
+        }
+    }
+}
+
+impl Serialize for Leftovers {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.texts.is_empty() {
+            true => self.cells.serialize(serializer),
+            false => self.cells_as_read().serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Leftovers {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        serde_json::Map::deserialize(deserializer).map(|cells| Self {
+            texts: leaves(&cells)
+                .filter(|(_, value)| !value.is_string())
+                .filter_map(|(path, value)| guessed_text(&path).map(|text| (path, (value, text))))
+                .collect(),
+            cells,
+            prefix: map_path().unwrap_or_default(),
+        })
+    }
+}
+
+/// every cell of `cells`, by path below them
+fn leaves(
+    cells: &serde_json::Map<String, Value>,
+) -> impl Iterator<Item = (FieldPath<'static>, Value)> + '_ {
+    cells.iter().flat_map(|(key, value)| {
+        flattened_iter(
+            FieldPath::default().join(Segment::Field(Cow::Borrowed(key))),
+            value.clone(),
+        )
+    })
+}
+
+impl Leftovers {
+    /// Leftover cells under their full flattened header names, e.g. `outer__note` for a
+    /// `note` column left over by the struct at `outer`. Empty cells are skipped.
+    pub fn columns(&self, config: &FlattenConfig) -> Vec<(String, String)> {
+        leaves(&self.cells_as_read())
+            .filter_map(|(path, value)| {
+                match value {
+                    Value::Null => None,
+                    Value::String(text) => Some(text),
+                    other => Some(other.to_string()),
+                }
+                .map(|text| (config.join(&self.prefix, &path.render(config)), text))
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    /// The cells with the ones still holding what was read put back as their text
+    fn cells_as_read(&self) -> serde_json::Map<String, Value> {
+        self.cells
+            .iter()
+            .map(|(key, value)| {
+                let path = FieldPath::default().join(Segment::Field(Cow::Owned(key.clone())));
+                (key.clone(), self.as_read(&path, value))
+            })
+            .collect()
+    }
+
+    fn as_read(&self, path: &FieldPath<'static>, value: &Value) -> Value {
+        match value {
+            Value::Object(map) => map
+                .iter()
+                .map(|(key, value)| {
+                    let path = path.join(Segment::Field(Cow::Owned(key.clone())));
+                    (key.clone(), self.as_read(&path, value))
+                })
+                .collect::<serde_json::Map<_, _>>()
+                .pipe(Value::Object),
+            Value::Array(values) => values
+                .iter()
+                .enumerate()
+                .map(|(idx, value)| self.as_read(&path.join(Segment::Idx(idx)), value))
+                .collect::<Vec<_>>()
+                .pipe(Value::Array),
+            leaf => match self.texts.get(path) {
+                Some((read, text)) if read == leaf => Value::String(text.clone()),
+                _ => leaf.clone(),
+            },
+        }
+    }
+}
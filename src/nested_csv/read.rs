@@ -1,16 +1,40 @@
 use {
-    crate::serde::flattened_map_deserializer::{self, FlattenedMapDeserializer},
+    crate::{
+        flatten_json_value::{
+            ConfigError, FieldPath, FlattenConfig, Segment,
+            schema::{self, Schema},
+        },
+        nested_csv::headers::{HeaderMapping, HeaderMatching},
+        serde::{
+            flattened_map_deserializer::{self, FlattenedMapDeserializer},
+            header_tree::{HeaderTree, Node},
+        },
+    },
     csv::StringRecord,
     indexmap::IndexMap,
     serde::de::DeserializeOwned,
-    std::{fmt::Debug, io::Read, marker::PhantomData},
-    tap::Tap,
+    std::{
+        collections::{BTreeMap, HashMap},
+        fmt::Debug,
+        io::Read,
+        marker::PhantomData,
+    },
+    tap::{Pipe, Tap},
+    tracing::warn,
 };
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    #[error("Headers-parsing reader is required")]
+    #[error("Headers-parsing reader is required, or explicit headers")]
     NoHeaders,
+    #[error("Invalid config")]
+    InvalidConfig(#[source] ConfigError),
+    #[error("Could not trace the columns of the type")]
+    TracingSchema(#[source] schema::Error),
+    #[error(
+        "The columns at {paths:?} depend on the data, sequences need a `FlattenConfig::array_widths` entry, other parts an opaque path"
+    )]
+    DataDependentColumns { paths: Vec<String> },
     #[error("Reading headers")]
     ReadingHeaders(#[source] csv::Error),
     #[error("Reading a single record")]
@@ -21,22 +45,237 @@ pub enum Error {
         source: flattened_map_deserializer::Error,
         value: IndexMap<String, String>,
     },
+    #[error("Header '{header}' appears more than once, in columns {first} and {second}")]
+    DuplicateHeader {
+        header: String,
+        first: usize,
+        second: usize,
+    },
+    #[error("Columns unknown to the type: {columns:?}")]
+    UnknownColumns { columns: Vec<String> },
     #[error("Missing field '{field}' (idx: {idx}) for record number {record}")]
     MissingField {
         idx: usize,
         field: String,
         record: usize,
     },
+    #[error("Record number {record} has {cells} cells, more than the {headers} headers")]
+    ExtraCells {
+        cells: usize,
+        headers: usize,
+        record: usize,
+    },
 }
 
 type Result<T> = std::result::Result<T, self::Error>;
 
+/// What to do with columns the type has no place for (see [`Schema::covers`]), decided
+/// once for the header line
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownColumns {
+    /// offer every column to the type, which may still ignore it
+    #[default]
+    Keep,
+    /// only the columns of the type are read into records, cheaper for wide files
+    Skip,
+    /// same as [`UnknownColumns::Keep`], with a warning listing the unknown columns
+    Warn,
+    /// fail with [`Error::UnknownColumns`]
+    Error,
+}
+
+/// Meaning of a header appearing more than once
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RepeatedHeaders {
+    /// fail with [`Error::DuplicateHeader`], instead of one of the cells being silently dropped
+    #[default]
+    Error,
+    /// the columns are the elements of an array, e.g. `tag,tag,tag` is read as
+    /// `tag__idx-0,tag__idx-1,tag__idx-2`
+    Array,
+}
+
+/// How fields of `T` without any column in the file are read. By default they are an
+/// error, unless they are an `Option` or have `#[serde(default)]`.
+///
+/// A fallback takes precedence over `#[serde(default)]`, enums can only be filled by one.
+/// Fields without a fallback are left out of their struct, so `Default` is only used where
+/// serde is told to use it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MissingColumns {
+    /// read structs without any column from no fields (so e.g. a `#[serde(default)]` on the
+    /// struct applies), sequences and maps as empty. This replaces a `#[serde(default)]` on
+    /// the field holding them, leaves (numbers, strings...) are always left to serde.
+    pub fill_defaults: bool,
+    /// cell read for a missing path, e.g. `currency` => `EUR`
+    pub fallbacks: BTreeMap<String, String>,
+}
+
+impl MissingColumns {
+    /// see [`MissingColumns::fill_defaults`]
+    pub fn with_defaults(self) -> Self {
+        Self {
+            fill_defaults: true,
+            ..self
+        }
+    }
+
+    /// see [`MissingColumns::fallbacks`]
+    pub fn with_fallback(self, path: impl Into<String>, cell: impl Into<String>) -> Self {
+        self.tap_mut(|missing| {
+            missing.fallbacks.insert(path.into(), cell.into());
+        })
+    }
+
+    /// the field at `path`, which has no column, gets filled: either as a whole or some of
+    /// the fields below it
+    pub fn fills(&self, schema: &Schema, path: &str, config: &FlattenConfig) -> bool {
+        (self.fill_defaults
+            && matches!(schema, Schema::Struct(_) | Schema::Seq(_) | Schema::Map(_)))
+            || self.fallbacks.keys().any(|fallback| {
+                fallback.strip_prefix(path).is_some_and(|rest| {
+                    rest.is_empty() || rest.starts_with(config.separator.as_ref())
+                })
+            })
+    }
+
+    /// The fields of `schema` the `tree` has no column for which get filled
+    fn filled_fields(
+        &self,
+        schema: &Schema,
+        tree: &HeaderTree,
+        config: &FlattenConfig,
+    ) -> FilledFields {
+        FilledFields {
+            fallbacks: self.fallbacks.clone(),
+            ..Default::default()
+        }
+        .tap_mut(|filled| {
+            self.collect_filled(
+                schema,
+                "",
+                Some(tree.node(HeaderTree::ROOT)),
+                tree,
+                config,
+                filled,
+            )
+        })
+    }
+
+    /// `path` is the rendered path of `schema`, `node` its node unless it has no column
+    fn collect_filled(
+        &self,
+        schema: &Schema,
+        path: &str,
+        node: Option<&Node>,
+        tree: &HeaderTree,
+        config: &FlattenConfig,
+        filled: &mut FilledFields,
+    ) {
+        let child = |segment: Segment<'static>| {
+            let child = node
+                .and_then(|node| node.children.get(&segment))
+                .map(|child| tree.node(*child));
+            let path = child.map_or_else(
+                || config.join(path, &segment.render(config)),
+                |child| child.path.clone(),
+            );
+            (path, child)
+        };
+        match schema {
+            Schema::Option(inner) => self.collect_filled(inner, path, node, tree, config, filled),
+            Schema::Struct(fields) => fields.iter().for_each(|(field, schema)| {
+                match child(Segment::Field((*field).into())) {
+                    (path, Some(child)) => {
+                        self.collect_filled(schema, &path, Some(child), tree, config, filled)
+                    }
+                    (field_path, None) if self.fills(schema, &field_path, config) => {
+                        if node.is_some() {
+                            filled.outermost.push(field_path.clone());
+                        }
+                        self.collect_filled(schema, &field_path, None, tree, config, filled);
+                        filled
+                            .fields
+                            .entry(path.to_string())
+                            .or_default()
+                            .push((*field, field_path));
+                    }
+                    (_, None) => {}
+                }
+            }),
+            Schema::Tuple(elements) => elements.iter().enumerate().for_each(|(idx, schema)| {
+                let (path, child) = child(Segment::Idx(idx));
+                self.collect_filled(schema, &path, child, tree, config, filled)
+            }),
+            Schema::Enum(variants) => variants.iter().for_each(|(variant, schema)| {
+                if let (path, Some(child)) = child(Segment::Field((*variant).into())) {
+                    self.collect_filled(schema, &path, Some(child), tree, config, filled)
+                }
+            }),
+            Schema::Seq(element) | Schema::Map(element) => node
+                .into_iter()
+                .flat_map(|node| node.children.values())
+                .map(|child| tree.node(*child))
+                .for_each(|child| {
+                    self.collect_filled(element, &child.path, Some(child), tree, config, filled)
+                }),
+            Schema::Leaf | Schema::Unit | Schema::Any => {}
+        }
+    }
+}
+
+/// Fields of `T` without any column which get filled, found once from the headers, see
+/// [`MissingColumns`]
+#[derive(Debug, Default)]
+pub(crate) struct FilledFields {
+    /// filled fields with their paths, by the path of their struct (the ones inside other
+    /// filled fields too)
+    fields: HashMap<String, Vec<(&'static str, String)>>,
+    /// the outermost ones, see [`NestedCsvReader::filled_paths`]
+    outermost: Vec<String>,
+    /// see [`MissingColumns::fallbacks`]
+    fallbacks: BTreeMap<String, String>,
+}
+
+impl FilledFields {
+    /// the filled fields of the struct at `path`, with their paths
+    pub(crate) fn fields(&self, path: &str) -> &[(&'static str, String)] {
+        self.fields.get(path).map_or(&[], Vec::as_slice)
+    }
+
+    /// the cell read for the field at `path`, if any
+    pub(crate) fn fallback(&self, path: &str) -> Option<&str> {
+        self.fallbacks.get(path).map(String::as_str)
+    }
+}
+
+/// Reads flattened csv rows into nested records.
+///
+/// The `with_*` options may be set in any order, they are applied together to the headers
+/// when the first record is read (or [`NestedCsvReader::filled_paths`] is asked for).
 pub struct NestedCsvReader<R, T> {
     headers: StringRecord,
     reader: csv::Reader<R>,
     count: usize,
     _marker: PhantomData<T>,
     rec: StringRecord,
+    config: FlattenConfig,
+    unknown_columns: UnknownColumns,
+    header_mapping: HeaderMapping,
+    repeated_headers: RepeatedHeaders,
+    missing: MissingColumns,
+    /// built from the headers and the options once, see [`NestedCsvReader::columns`]
+    columns: Option<Columns>,
+}
+
+/// How the columns of the file are read, shared by every record
+struct Columns {
+    /// flattened path of every column, the headers unless mapped by [`HeaderMapping`]
+    paths: Vec<String>,
+    /// paths parsed into a tree, without the columns skipped by [`UnknownColumns::Skip`]
+    tree: HeaderTree,
+    /// fields filled for every record, see [`MissingColumns`]
+    filled: FilledFields,
 }
 
 #[extension_traits::extension(pub trait CsvReaderEnableNestedExt)]
@@ -44,6 +283,25 @@ impl<R: Read> csv::Reader<R> {
     fn enable_nested<T: DeserializeOwned + Debug>(self) -> Result<NestedCsvReader<R, T>> {
         NestedCsvReader::new(self)
     }
+    fn enable_nested_with_config<T: DeserializeOwned + Debug>(
+        self,
+        config: FlattenConfig,
+    ) -> Result<NestedCsvReader<R, T>> {
+        NestedCsvReader::with_config(self, config)
+    }
+    fn enable_nested_with_headers<T: DeserializeOwned + Debug>(
+        self,
+        headers: impl IntoIterator<Item = impl AsRef<str>>,
+        config: FlattenConfig,
+    ) -> Result<NestedCsvReader<R, T>> {
+        NestedCsvReader::with_headers(self, headers, config)
+    }
+    fn enable_nested_with_type_headers<T: DeserializeOwned + Debug>(
+        self,
+        config: FlattenConfig,
+    ) -> Result<NestedCsvReader<R, T>> {
+        NestedCsvReader::with_type_headers(self, config)
+    }
 }
 
 impl<R: Read, T: DeserializeOwned + Debug> NestedCsvReader<R, T> {
@@ -51,50 +309,78 @@ impl<R: Read, T: DeserializeOwned + Debug> NestedCsvReader<R, T> {
         self.reader.into_inner()
     }
 
+    /// Records of the file. The first item is the error of applying the options to the
+    /// headers, if any, and then the iterator ends.
     pub fn deserialize(&mut self) -> impl Iterator<Item = self::Result<T>> + '_ {
-        std::iter::from_fn(|| {
-            self.reader
-                .read_record(&mut self.rec)
-                .map_err(self::Error::ReadingRecord)
-                .and_then(|has_record| {
-                    has_record
-                        .then(|| {
-                            // Build a flat map of String -> String
-                            self.headers
-                                .iter()
-                                .enumerate()
-                                .map(|(idx, header)| {
-                                    self.rec
-                                        .get(idx)
-                                        .ok_or_else(|| self::Error::MissingField {
-                                            idx,
-                                            field: header.to_string(),
-                                            record: self.count,
-                                        })
-                                        .map(|value| (header.to_string(), value.to_string()))
-                                })
-                                .collect::<Result<IndexMap<String, String>>>()
-                                .and_then(|map| {
-                                    T::deserialize(FlattenedMapDeserializer::new(&map)).map_err(
-                                        |source| self::Error::DeserializingFlattened {
-                                            source,
-                                            value: map,
-                                        },
+        let columns = self.columns().map(|_| ());
+        let failed = columns.is_err();
+        columns
+            .err()
+            .map(Err)
+            .into_iter()
+            .chain(std::iter::from_fn(move || {
+                let columns = match (failed, self.columns.as_ref()) {
+                    (false, Some(columns)) => columns,
+                    _ => return None,
+                };
+                self.reader
+                    .read_record(&mut self.rec)
+                    .map_err(self::Error::ReadingRecord)
+                    .and_then(|has_record| {
+                        has_record
+                            .then(|| {
+                                match self.rec.len().cmp(&self.headers.len()) {
+                                    std::cmp::Ordering::Less => Err(self::Error::MissingField {
+                                        idx: self.rec.len(),
+                                        field: self.headers[self.rec.len()].to_string(),
+                                        record: self.count,
+                                    }),
+                                    std::cmp::Ordering::Greater => Err(self::Error::ExtraCells {
+                                        cells: self.rec.len(),
+                                        headers: self.headers.len(),
+                                        record: self.count,
+                                    }),
+                                    std::cmp::Ordering::Equal => Ok(()),
+                                }
+                                .and_then(|()| {
+                                    FlattenedMapDeserializer::new(
+                                        &columns.tree,
+                                        &self.config,
+                                        &self.rec,
                                     )
+                                    .with_filled_fields(&columns.filled)
+                                    .deserialize_retrying::<T>()
+                                    .map_err(|source| {
+                                        self::Error::DeserializingFlattened {
+                                            source,
+                                            value: columns
+                                                .paths
+                                                .iter()
+                                                .zip(self.rec.iter())
+                                                .map(|(path, value)| {
+                                                    (path.clone(), value.to_string())
+                                                })
+                                                .collect(),
+                                        }
+                                    })
                                 })
-                        })
-                        .transpose()
-                })
-                .transpose()
-                .tap(|v| {
-                    if matches!(v, Some(Ok(_))) {
-                        self.count += 1
-                    }
-                })
-        })
+                            })
+                            .transpose()
+                    })
+                    .transpose()
+                    .tap(|v| {
+                        if matches!(v, Some(Ok(_))) {
+                            self.count += 1
+                        }
+                    })
+            }))
     }
 
     pub fn new(reader: csv::Reader<R>) -> Result<Self> {
+        Self::with_config(reader, FlattenConfig::default())
+    }
+
+    pub fn with_config(reader: csv::Reader<R>, config: FlattenConfig) -> Result<Self> {
         (match reader.has_headers() {
             true => Ok(reader),
             false => Err(self::Error::NoHeaders),
@@ -106,12 +392,219 @@ impl<R: Read, T: DeserializeOwned + Debug> NestedCsvReader<R, T> {
                 .cloned()
                 .map(|headers| (reader, headers))
         })
-        .map(|(reader, headers)| Self {
+        .and_then(|(reader, headers)| Self::from_headers(reader, headers, config))
+    }
+
+    /// Reads the columns as `headers` (flattened paths, e.g. `user__address__city`), for
+    /// files without a header line (`has_headers(false)`).
+    ///
+    /// If the reader does expect a header line, that line is skipped and `headers` are
+    /// used instead.
+    pub fn with_headers(
+        reader: csv::Reader<R>,
+        headers: impl IntoIterator<Item = impl AsRef<str>>,
+        config: FlattenConfig,
+    ) -> Result<Self> {
+        let headers = headers
+            .into_iter()
+            .fold(StringRecord::new(), |headers, header| {
+                headers.tap_mut(|headers| headers.push_field(header.as_ref()))
+            });
+        Self::from_headers(reader, headers, config)
+    }
+
+    /// Same as [`NestedCsvReader::with_headers`], with every column of `T` as the headers
+    /// (see [`Schema::headers`]), in the order `NestedCsvWriter::with_type_headers` writes them.
+    /// Fails when a part of `T` has no fixed set of columns (see [`Schema::dynamic_paths`]).
+    pub fn with_type_headers(reader: csv::Reader<R>, config: FlattenConfig) -> Result<Self> {
+        Schema::of::<T>()
+            .map_err(self::Error::TracingSchema)
+            .and_then(|schema| match schema.dynamic_paths(&config) {
+                paths if paths.is_empty() => Ok(schema.headers(&config)),
+                paths => Err(self::Error::DataDependentColumns { paths }),
+            })
+            .and_then(|headers| Self::with_headers(reader, headers, config))
+    }
+
+    /// Sets what happens to columns `T` doesn't know about. The headers are checked once,
+    /// no work is added per record.
+    pub fn with_unknown_columns(self, unknown_columns: UnknownColumns) -> Self {
+        Self {
+            unknown_columns,
+            columns: None,
+            ..self
+        }
+    }
+
+    /// Reads the headers through `mapping` (renames, aliases, labels, case-insensitive
+    /// matching). [`NestedCsvReader::with_unknown_columns`] and
+    /// [`NestedCsvReader::with_missing_columns`] look at the mapped paths.
+    pub fn with_header_mapping(self, header_mapping: HeaderMapping) -> Self {
+        Self {
+            header_mapping,
+            columns: None,
+            ..self
+        }
+    }
+
+    /// Sets how a header appearing more than once is read, after [`HeaderMapping`]
+    pub fn with_repeated_headers(self, repeated_headers: RepeatedHeaders) -> Self {
+        Self {
+            repeated_headers,
+            columns: None,
+            ..self
+        }
+    }
+
+    /// Sets how fields without any column are filled
+    pub fn with_missing_columns(self, missing: MissingColumns) -> Self {
+        Self {
+            missing,
+            columns: None,
+            ..self
+        }
+    }
+
+    /// Paths of the fields of `T` with no column in the file, filled according to
+    /// [`NestedCsvReader::with_missing_columns`]
+    pub fn filled_paths(&mut self) -> Result<&[String]> {
+        self.columns()
+            .map(|columns| columns.filled.outermost.as_slice())
+    }
+
+    /// Applies the options to the headers, the first time it's called
+    fn columns(&mut self) -> Result<&Columns> {
+        match self.columns {
+            Some(ref columns) => Ok(columns),
+            None => self
+                .build_columns()
+                .map(|columns| &*self.columns.insert(columns)),
+        }
+    }
+
+    fn build_columns(&self) -> Result<Columns> {
+        let needs_schema = self.header_mapping.matching != HeaderMatching::Exact
+            || self.unknown_columns != UnknownColumns::Keep
+            || self.missing != MissingColumns::default();
+        let schema = match needs_schema {
+            true => Schema::of::<T>()
+                .map_err(self::Error::TracingSchema)?
+                .pipe(Some),
+            false => None,
+        };
+        let paths = column_paths(
+            self.headers.iter().map(|header| {
+                self.header_mapping
+                    .path(header, schema.as_ref(), &self.config)
+            }),
+            self.repeated_headers,
+            &self.config,
+        )?;
+        let known = match (self.unknown_columns, &schema) {
+            (UnknownColumns::Keep, _) | (_, None) => vec![true; paths.len()],
+            (_, Some(schema)) => paths
+                .iter()
+                .map(|path| schema.covers(&FieldPath::parse(path, &self.config), &self.config))
+                .collect(),
+        };
+        let unknown = || {
+            self.headers
+                .iter()
+                .zip(&known)
+                .filter(|(_, known)| !**known)
+                .map(|(header, _)| header.to_string())
+                .collect::<Vec<_>>()
+        };
+        match self.unknown_columns {
+            UnknownColumns::Error if known.contains(&false) => {
+                return Err(self::Error::UnknownColumns { columns: unknown() });
+            }
+            UnknownColumns::Warn if known.contains(&false) => warn!(
+                columns = ?unknown(),
+                "columns unknown to {}",
+                std::any::type_name::<T>()
+            ),
+            _ => {}
+        }
+        let tree = match self.unknown_columns {
+            UnknownColumns::Skip => HeaderTree::with_columns(
+                paths
+                    .iter()
+                    .map(String::as_str)
+                    .enumerate()
+                    .filter(|(column, _)| known[*column]),
+                &self.config,
+            ),
+            _ => HeaderTree::new(paths.iter().map(String::as_str), &self.config),
+        };
+        let filled = match &schema {
+            Some(schema) => self.missing.filled_fields(schema, &tree, &self.config),
+            None => FilledFields::default(),
+        };
+        Ok(Columns {
+            paths,
+            tree,
+            filled,
+        })
+    }
+
+    fn from_headers(
+        reader: csv::Reader<R>,
+        headers: StringRecord,
+        config: FlattenConfig,
+    ) -> Result<Self> {
+        config.validate().map_err(self::Error::InvalidConfig)?;
+        Ok(Self {
             headers,
             reader,
             rec: Default::default(),
             _marker: PhantomData,
             count: 0,
+            config,
+            unknown_columns: UnknownColumns::default(),
+            header_mapping: HeaderMapping::default(),
+            repeated_headers: RepeatedHeaders::default(),
+            missing: MissingColumns::default(),
+            columns: None,
         })
     }
 }
+
+/// Flattened paths of the columns, a header appearing more than once is an error or
+/// becomes the elements of an array, see [`RepeatedHeaders`]
+fn column_paths(
+    headers: impl IntoIterator<Item = String>,
+    repeated_headers: RepeatedHeaders,
+    config: &FlattenConfig,
+) -> Result<Vec<String>> {
+    let mut paths = headers.into_iter().collect::<Vec<_>>();
+    let columns = paths
+        .iter()
+        .enumerate()
+        .fold(
+            IndexMap::<&str, Vec<usize>>::new(),
+            |columns, (column, path)| {
+                columns.tap_mut(|columns| columns.entry(path.as_str()).or_default().push(column))
+            },
+        )
+        .into_values()
+        .filter(|columns| columns.len() > 1)
+        .collect::<Vec<_>>();
+    match (repeated_headers, columns.first()) {
+        (_, None) => Ok(paths),
+        (RepeatedHeaders::Error, Some(columns)) => Err(self::Error::DuplicateHeader {
+            header: paths[columns[0]].clone(),
+            first: columns[0],
+            second: columns[1],
+        }),
+        (RepeatedHeaders::Array, Some(_)) => {
+            columns.iter().for_each(|columns| {
+                columns.iter().enumerate().for_each(|(idx, column)| {
+                    paths[*column] =
+                        config.join(&paths[*column], &Segment::Idx(idx).render(config));
+                })
+            });
+            Ok(paths)
+        }
+    }
+}
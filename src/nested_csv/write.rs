@@ -1,24 +1,72 @@
 use {
-    crate::flatten_json_value::flatten::flattened,
-    serde::Serialize,
+    crate::{
+        flatten_json_value::{
+            ConfigError, FieldPath, FlattenConfig, flatten,
+            schema::{self, Schema},
+            serializer::{FlatSerializer, FlatSink, Leaf},
+        },
+        nested_csv::headers::HeaderMapping,
+    },
+    indexmap::IndexSet,
+    serde::{Serialize, de::DeserializeOwned},
     serde_json::Map,
     std::{fmt::Debug, io::Write, marker::PhantomData},
-    tap::Pipe,
+    tap::{Pipe, Tap},
 };
 
+/// Writes nested records as flattened csv rows.
+///
+/// Whatever is still pending (headers of an empty file, rows buffered by
+/// [`UnknownKeys::Extend`]) is written by [`NestedCsvWriter::into_inner`], or when the writer
+/// is dropped. Dropping ignores errors, call `into_inner` to see them.
 pub struct NestedCsvWriter<W: Write, T: Serialize + Debug> {
-    writer: csv::Writer<W>,
-    headers: Option<Vec<String>>,
+    /// only taken by [`NestedCsvWriter::into_inner`]
+    writer: Option<csv::Writer<W>>,
+    headers: Option<IndexSet<String>>,
+    headers_written: bool,
+    /// cells of the record being written, reused between records
+    row: Vec<String>,
+    filled: Vec<bool>,
+    /// rows held back until the final set of columns is known, see [`UnknownKeys::Extend`]
+    buffered: Vec<Vec<String>>,
+    /// see [`NestedCsvWriter::with_max_buffered_rows`]
+    max_buffered_rows: Option<usize>,
+    unknown_keys: UnknownKeys,
+    /// headers written for the flattened paths
+    header_mapping: HeaderMapping,
     count: usize,
+    config: FlattenConfig,
     _marker: PhantomData<T>,
 }
 
+/// What to do with a flattened key which has no column in the headers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownKeys {
+    /// fail with [`Error::ExtraValuesComparedToHeaders`]
+    #[default]
+    Error,
+    /// skip the value
+    Drop,
+    /// add a column for it. Rows are kept in memory (see
+    /// [`NestedCsvWriter::with_max_buffered_rows`]) and written by
+    /// [`NestedCsvWriter::into_inner`] or on drop, every row aligned to the union of all columns.
+    Extend,
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Could not convert into inner error:\n{0}")]
     IntoInner(Box<str>),
-    #[error("Could not serialize the struct to value")]
-    SerializingToValue(#[source] serde_json::Error),
+    #[error("Invalid config")]
+    InvalidConfig(#[source] ConfigError),
+    #[error("Could not flatten the serialized value")]
+    Flattening(#[source] flatten::Error),
+    #[error("Could not trace the columns of the type")]
+    TracingSchema(#[source] schema::Error),
+    #[error(
+        "The columns at {paths:?} depend on the data, sequences need a `FlattenConfig::array_widths` entry, other parts an opaque path"
+    )]
+    DataDependentColumns { paths: Vec<String> },
     #[error("Could not write headers")]
     WritingHeaders(#[source] csv::Error),
     #[error("Writing record #{idx}")]
@@ -27,6 +75,8 @@ pub enum Error {
         #[source]
         source: csv::Error,
     },
+    #[error("More than {limit} rows would be kept in memory for UnknownKeys::Extend")]
+    TooManyBufferedRows { limit: usize },
     #[error("Extra headers compared to headers line:\n{extra_values:#?}")]
     ExtraValuesComparedToHeaders {
         extra_values: Map<String, serde_json::Value>,
@@ -40,6 +90,18 @@ impl<W: Write> csv::Writer<W> {
     fn enable_nested<T: Serialize + Debug>(self) -> NestedCsvWriter<W, T> {
         NestedCsvWriter::new(self)
     }
+    fn enable_nested_with_config<T: Serialize + Debug>(
+        self,
+        config: FlattenConfig,
+    ) -> NestedCsvWriter<W, T> {
+        NestedCsvWriter::with_config(self, config)
+    }
+    fn enable_nested_with_type_headers<T: Serialize + DeserializeOwned + Debug>(
+        self,
+        config: FlattenConfig,
+    ) -> Result<NestedCsvWriter<W, T>> {
+        NestedCsvWriter::with_type_headers(self, config)
+    }
 }
 
 impl<W, T> NestedCsvWriter<W, T>
@@ -47,65 +109,250 @@ where
     W: Write,
     T: Serialize + Debug,
 {
-    pub fn into_inner(self) -> Result<W> {
+    /// Writes out whatever is still pending (headers, buffered rows) and returns the writer
+    pub fn into_inner(mut self) -> Result<W> {
+        self.write_pending().and_then(|()| {
+            self.writer
+                .take()
+                .expect("the writer is only taken here")
+                .into_inner()
+                .map_err(|e| self::Error::IntoInner(format!("{e:#?}").pipe(Box::from)))
+        })
+    }
+
+    /// Headers not written yet and the rows buffered by [`UnknownKeys::Extend`]
+    fn write_pending(&mut self) -> Result<()> {
+        let width = self.headers.as_ref().map_or(0, IndexSet::len);
+        self.write_pending_headers().and_then(|()| {
+            std::mem::take(&mut self.buffered)
+                .into_iter()
+                .enumerate()
+                .try_for_each(|(idx, mut row)| {
+                    row.resize(width, self.config.null_cell().to_string());
+                    self.writer_mut().write_record(&row).map_err(|source| {
+                        self::Error::WritingRecord {
+                            idx: idx + 1,
+                            source,
+                        }
+                    })
+                })
+        })
+    }
+
+    fn writer_mut(&mut self) -> &mut csv::Writer<W> {
         self.writer
-            .into_inner()
-            .map_err(|e| self::Error::IntoInner(format!("{e:#?}").pipe(Box::from)))
+            .as_mut()
+            .expect("the writer is only taken by into_inner")
     }
 
     pub fn new(writer: csv::Writer<W>) -> Self {
+        Self::with_config(writer, FlattenConfig::default())
+    }
+
+    pub fn with_config(writer: csv::Writer<W>, config: FlattenConfig) -> Self {
         Self {
-            writer,
+            writer: Some(writer),
             count: 0usize,
             headers: None,
+            headers_written: false,
+            row: Vec::new(),
+            filled: Vec::new(),
+            buffered: Vec::new(),
+            max_buffered_rows: None,
+            unknown_keys: UnknownKeys::default(),
+            header_mapping: HeaderMapping::default(),
+            config,
             _marker: PhantomData,
         }
     }
 
+    /// Uses every column of `T` (see [`Schema::headers`]) as the headers, instead of
+    /// the keys of the first record.
+    ///
+    /// A `None` or a different enum variant in any record no longer decides the columns,
+    /// and a file without any records still gets its header line. Fails when a part of `T`
+    /// has no fixed set of columns (see [`Schema::dynamic_paths`]).
+    pub fn with_type_headers(writer: csv::Writer<W>, config: FlattenConfig) -> Result<Self>
+    where
+        T: DeserializeOwned,
+    {
+        config.validate().map_err(self::Error::InvalidConfig)?;
+        Schema::of::<T>()
+            .map_err(self::Error::TracingSchema)
+            .and_then(|schema| match schema.dynamic_paths(&config) {
+                paths if paths.is_empty() => Ok(schema.headers(&config)),
+                paths => Err(self::Error::DataDependentColumns { paths }),
+            })
+            .map(|headers| {
+                Self::with_config(writer, config).tap_mut(|this| {
+                    this.headers = Some(headers.into_iter().collect());
+                })
+            })
+    }
+
+    /// Sets what happens to keys which aren't a part of the headers
+    pub fn with_unknown_keys(self, unknown_keys: UnknownKeys) -> Self {
+        self.tap_mut(|this| this.unknown_keys = unknown_keys)
+    }
+
+    /// Fails a record which would make more than `limit` rows kept in memory by
+    /// [`UnknownKeys::Extend`], instead of buffering the whole file
+    pub fn with_max_buffered_rows(self, limit: usize) -> Self {
+        self.tap_mut(|this| this.max_buffered_rows = Some(limit))
+    }
+
+    /// Writes the header line through `mapping` (renames, display labels), the records are
+    /// still matched with the flattened paths
+    pub fn with_header_mapping(self, header_mapping: HeaderMapping) -> Self {
+        self.tap_mut(|this| this.header_mapping = header_mapping)
+    }
+
+    /// Flushes the underlying writer. Rows buffered by [`UnknownKeys::Extend`] stay
+    /// buffered until [`NestedCsvWriter::into_inner`].
     pub fn flush(&mut self) -> std::io::Result<()> {
-        self.writer.flush()
+        self.writer_mut().flush()
     }
 
     pub fn serialize(&mut self, item: &T) -> Result<()> {
-        serde_json::to_value(item)
-            .map_err(self::Error::SerializingToValue)
-            .map(flattened)
-            .and_then(|mut item| -> Result<_> {
-                if self.headers.is_none() {
-                    let headers = item.keys().cloned().collect::<Vec<_>>();
+        if self.count == 0 {
+            self.config.validate().map_err(self::Error::InvalidConfig)?;
+        }
+        // without headers the first record decides them
+        let (unknown_keys, learning) = match self.headers.is_some() {
+            true => (self.unknown_keys, false),
+            false => (UnknownKeys::Extend, true),
+        };
+        let headers = self.headers.get_or_insert_with(IndexSet::new);
+        self.row.resize(headers.len(), String::new());
+        self.row.iter_mut().for_each(String::clear);
+        self.filled.clear();
+        self.filled.resize(headers.len(), false);
+        FlatSerializer::new(
+            &self.config,
+            RowSink {
+                headers,
+                row: &mut self.row,
+                filled: &mut self.filled,
+                unknown_keys,
+                learning,
+                extra_values: Map::new(),
+            },
+        )
+        .pipe(|mut flat| item.serialize(&mut flat).map(|()| flat.into_sink()))
+        .map_err(self::Error::Flattening)
+        .and_then(|RowSink { extra_values, .. }| {
+            extra_values
+                .is_empty()
+                .then_some(())
+                .ok_or(self::Error::ExtraValuesComparedToHeaders { extra_values })
+        })
+        .map(|()| {
+            // columns this record has no value for
+            self.row
+                .iter_mut()
+                .zip(&self.filled)
+                .filter(|(_, filled)| !**filled)
+                .for_each(|(cell, _)| cell.push_str(self.config.null_cell()))
+        })
+        .and_then(|()| match (self.unknown_keys, self.max_buffered_rows) {
+            (UnknownKeys::Extend, Some(limit)) if self.buffered.len() >= limit => {
+                Err(self::Error::TooManyBufferedRows { limit })
+            }
+            (UnknownKeys::Extend, _) => {
+                self.count += 1;
+                self.buffered.push(self.row.clone());
+                Ok(())
+            }
+            (UnknownKeys::Error | UnknownKeys::Drop, _) => {
+                self.count += 1;
+                let idx = self.count;
+                self.write_pending_headers().and_then(|()| {
                     self.writer
-                        .write_record(&headers)
-                        .map_err(self::Error::WritingHeaders)?;
-                    self.headers = Some(headers);
+                        .as_mut()
+                        .expect("the writer is only taken by into_inner")
+                        .write_record(&self.row)
+                        .map_err(|source| self::Error::WritingRecord { idx, source })
+                })
+            }
+        })
+    }
+
+    fn write_pending_headers(&mut self) -> Result<()> {
+        match (self.headers_written, self.headers.as_ref()) {
+            (false, Some(headers)) => self
+                .writer
+                .as_mut()
+                .expect("the writer is only taken by into_inner")
+                .write_record(
+                    headers
+                        .iter()
+                        .map(|path| self.header_mapping.header(path, &self.config)),
+                )
+                .map_err(self::Error::WritingHeaders)
+                .map(|()| self.headers_written = true),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<W, T> Drop for NestedCsvWriter<W, T>
+where
+    W: Write,
+    T: Serialize + Debug,
+{
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            // errors can't be reported here, `into_inner` reports them
+            let _ = self.write_pending();
+        }
+    }
+}
+
+/// Puts every leaf straight into the cell of its header
+struct RowSink<'w> {
+    headers: &'w mut IndexSet<String>,
+    row: &'w mut Vec<String>,
+    filled: &'w mut Vec<bool>,
+    unknown_keys: UnknownKeys,
+    /// the headers are being collected from this record
+    learning: bool,
+    extra_values: Map<String, serde_json::Value>,
+}
+
+impl FlatSink for RowSink<'_> {
+    fn push(
+        &mut self,
+        _: &FieldPath<'_>,
+        key: &str,
+        leaf: Leaf<'_>,
+        config: &FlattenConfig,
+    ) -> std::result::Result<(), flatten::Error> {
+        match self.headers.get_index_of(key) {
+            Some(idx) if self.filled[idx] => Err(flatten::Error::KeyCollision {
+                key: key.to_string(),
+            }),
+            Some(idx) => {
+                self.filled[idx] = true;
+                leaf.write_cell(&mut self.row[idx], config);
+                Ok(())
+            }
+            // a missing value (e.g. `None` in place of a whole struct) has no cell of its own
+            None if leaf == Leaf::Null && !self.learning => Ok(()),
+            None => match self.unknown_keys {
+                UnknownKeys::Error => {
+                    self.extra_values.insert(key.to_string(), leaf.to_value());
+                    Ok(())
                 }
-                self.count += 1;
-                self.headers
-                    .as_ref()
-                    .expect("headers to be set above")
-                    .iter()
-                    .map(|h| item.remove(h.as_str()).unwrap_or(serde_json::Value::Null))
-                    .map(|f| match &f {
-                        serde_json::Value::Null => "".to_string(),
-                        serde_json::Value::Bool(bool) => bool.to_string(),
-                        serde_json::Value::Number(number) => number.to_string(),
-                        serde_json::Value::String(v) => v.to_string(),
-                        other => panic!("bad flattening: {other:#?}"),
-                    })
-                    .collect::<Vec<_>>()
-                    .pipe(|values| {
-                        item.is_empty().then_some(values).ok_or_else(|| {
-                            self::Error::ExtraValuesComparedToHeaders { extra_values: item }
-                        })
-                    })
-                    .and_then(|row| {
-                        self.writer.write_record(&row).map_err(|source| {
-                            self::Error::WritingRecord {
-                                idx: self.count,
-                                source,
-                            }
-                        })
-                    })
-            })
+                UnknownKeys::Drop => Ok(()),
+                UnknownKeys::Extend => {
+                    self.headers.insert(key.to_string());
+                    self.filled.push(true);
+                    self.row
+                        .push(String::new().tap_mut(|cell| leaf.write_cell(cell, config)));
+                    Ok(())
+                }
+            },
+        }
     }
 }
 
@@ -3,3 +3,5 @@ pub mod flattened_ref;
 pub mod flattened;
 
 pub mod flattened_map_deserializer;
+
+pub mod header_tree;
@@ -1,3 +1,5 @@
+pub mod guess_type;
+
 use {
     crate::Flattened,
     serde::{Deserialize, Serialize, de::DeserializeOwned},
@@ -0,0 +1,147 @@
+//! Guessing the type of a csv cell.
+//!
+//! Used when the target doesn't say what it expects (`deserialize_any`), e.g. for
+//! `#[serde(untagged)]` enums or `serde_json::Value` fields.
+
+/// Which types a cell may be guessed as, anything else stays a string
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuessTypes {
+    /// an empty cell is a null
+    pub null: bool,
+    /// `true` / `false`
+    pub bool: bool,
+    /// e.g. `-12`
+    pub integers: bool,
+    /// e.g. `1.5`, `-2e10`
+    pub floats: bool,
+    /// numbers with leading zeros (`01234`, e.g. zip codes) stay strings
+    pub keep_leading_zeros: bool,
+}
+
+/// Type guessed for a cell
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Guess {
+    Null,
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    /// a number that doesn't fit into the types above without losing digits
+    /// (e.g. `u128` ids or long decimals), the cell holds its exact text
+    Number,
+    Str,
+}
+
+impl GuessTypes {
+    pub const DEFAULT: Self = Self {
+        null: true,
+        bool: true,
+        integers: true,
+        floats: true,
+        keep_leading_zeros: true,
+    };
+
+    /// every cell is a string
+    pub const NONE: Self = Self {
+        null: false,
+        bool: false,
+        integers: false,
+        floats: false,
+        keep_leading_zeros: true,
+    };
+
+    pub fn guess(&self, cell: &str) -> Guess {
+        let digits = cell.strip_prefix('-').unwrap_or(cell);
+        let leading_zero = digits.len() > 1
+            && digits.starts_with('0')
+            && !digits[1..].starts_with(['.', 'e', 'E']);
+        match cell {
+            "" if self.null => Guess::Null,
+            "true" if self.bool => Guess::Bool(true),
+            "false" if self.bool => Guess::Bool(false),
+            _ if leading_zero && self.keep_leading_zeros => Guess::Str,
+            _ if self.integers && is_integer(digits) => cell
+                .parse::<u64>()
+                .map(Guess::U64)
+                .or_else(|_| cell.parse::<i64>().map(Guess::I64))
+                .unwrap_or(Guess::Number),
+            _ if self.floats && is_float(digits) => match cell.parse::<f64>() {
+                Ok(_) if significant_digits(digits) > f64::DIGITS as usize => Guess::Number,
+                Ok(v) if v.is_finite() => Guess::F64(v),
+                _ => Guess::Str,
+            },
+            _ => Guess::Str,
+        }
+    }
+}
+
+impl Default for GuessTypes {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+fn is_integer(digits: &str) -> bool {
+    !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// only plain decimal notation, so words like `inf` or `NaN` stay strings
+fn is_float(digits: &str) -> bool {
+    digits.starts_with(|c: char| c.is_ascii_digit())
+        && digits
+            .bytes()
+            .all(|b| b.is_ascii_digit() || matches!(b, b'.' | b'e' | b'E' | b'-' | b'+'))
+}
+
+/// digits of the mantissa, without the leading zeros
+fn significant_digits(digits: &str) -> usize {
+    digits
+        .split(['e', 'E'])
+        .next()
+        .unwrap_or_default()
+        .bytes()
+        .filter(u8::is_ascii_digit)
+        .skip_while(|b| *b == b'0')
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guess() {
+        let guess = GuessTypes::DEFAULT;
+        [
+            ("", Guess::Null),
+            ("true", Guess::Bool(true)),
+            ("false", Guess::Bool(false)),
+            ("True", Guess::Str),
+            ("42", Guess::U64(42)),
+            ("-42", Guess::I64(-42)),
+            ("0", Guess::U64(0)),
+            ("1.5", Guess::F64(1.5)),
+            ("-2e3", Guess::F64(-2000.0)),
+            ("0.5", Guess::F64(0.5)),
+            ("01234", Guess::Str),
+            ("99999999999999999999", Guess::Number),
+            ("-99999999999999999999", Guess::Number),
+            ("0.1234567890123456789", Guess::Number),
+            ("0.000123456789012345", Guess::F64(0.000123456789012345)),
+            ("1e999", Guess::Str),
+            ("inf", Guess::Str),
+            ("NaN", Guess::Str),
+            ("1.2.3", Guess::Str),
+            ("abc", Guess::Str),
+        ]
+        .into_iter()
+        .for_each(|(cell, expected)| assert_eq!(guess.guess(cell), expected, "{cell:?}"));
+
+        let zip_codes_are_numbers = GuessTypes {
+            keep_leading_zeros: false,
+            ..GuessTypes::DEFAULT
+        };
+        assert_eq!(zip_codes_are_numbers.guess("01234"), Guess::U64(1234));
+        assert_eq!(GuessTypes::NONE.guess("42"), Guess::Str);
+    }
+}
@@ -1,20 +1,114 @@
-//! Custom deserializer for flattened string maps.
+//! Custom deserializer for flattened csv records.
 //!
-//! This deserializer takes a `Map<String, String>` where keys are flattened paths
-//! (e.g., "user__address__city") and values are raw strings. It handles nested
+//! This deserializer takes a [`HeaderTree`] built from flattened header paths
+//! (e.g., "user__address__city") and a record of raw string cells. It handles nested
 //! path lookups and lets the target type decide how to parse string values.
 
 use {
-    indexmap::IndexMap,
+    super::header_tree::{HeaderTree, Node, NodeId},
+    crate::{
+        flatten_json_value::{
+            BytesEncoding, EmptyCells, FieldPath, FlattenConfig, Guess, MultiValue, Segment,
+        },
+        nested_csv::read::FilledFields,
+    },
+    csv::StringRecord,
     serde::{
         Deserializer,
-        de::{self, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Visitor},
+        de::{
+            self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess,
+            Unexpected, Visitor,
+        },
+    },
+    serde_json::de::StrRead,
+    std::{
+        borrow::Cow,
+        cell::RefCell,
+        collections::{BTreeSet, HashMap},
     },
-    std::borrow::Cow,
 };
 
-const JOIN_TAG: &str = "__";
-const ARR_PFX: &str = "idx-";
+thread_local! {
+    /// maps being visited on this thread, see [`map_path`] and [`guessed_text`]
+    static READING: RefCell<Reading> = Default::default();
+}
+
+#[derive(Default)]
+struct Reading {
+    /// rendered paths of the maps being visited, innermost last
+    maps: Vec<String>,
+    /// path syntax of the record being read, while any map is
+    syntax: Option<FlattenConfig>,
+    /// text of the cells given to `deserialize_any` as something else than a string, by
+    /// rendered path. Kept until the outermost map is done.
+    guessed: HashMap<String, String>,
+}
+
+/// Rendered path of the innermost map (struct) being read by a [`FlattenedMapDeserializer`]
+/// on this thread. Lets `#[serde(flatten)]` fields, which only see a buffered copy of the
+/// map, find out where their cells came from.
+pub(crate) fn map_path() -> Option<String> {
+    READING.with_borrow(|reading| reading.maps.last().cloned())
+}
+
+/// Text of the cell at `path` below the innermost map (see [`map_path`]), if it was read
+/// as something else than a string, e.g. `1.50` which became `1.5`
+pub(crate) fn guessed_text(path: &FieldPath<'_>) -> Option<String> {
+    READING.with_borrow(|reading| {
+        reading
+            .syntax
+            .as_ref()
+            .zip(reading.maps.last())
+            .and_then(|(syntax, map)| {
+                reading
+                    .guessed
+                    .get(&syntax.join(map, &path.render(syntax)))
+                    .cloned()
+            })
+    })
+}
+
+/// A map being visited until dropped, even when its visitor panics, see [`map_path`]
+struct VisitingMap;
+
+impl VisitingMap {
+    fn enter(path: String, config: &FlattenConfig) -> Self {
+        READING.with_borrow_mut(|reading| {
+            if reading.maps.is_empty() {
+                reading.syntax = Some(FlattenConfig {
+                    separator: config.separator.clone(),
+                    array_prefix: config.array_prefix.clone(),
+                    array_suffix: config.array_suffix.clone(),
+                    escape: config.escape,
+                    ..FlattenConfig::DEFAULT
+                });
+            }
+            reading.maps.push(path)
+        });
+        Self
+    }
+
+    /// Remembers the text of a cell given to `deserialize_any` as something else than a string
+    fn guessed(path: &str, text: &str) {
+        READING.with_borrow_mut(|reading| {
+            if !reading.maps.is_empty() {
+                reading.guessed.insert(path.to_string(), text.to_string());
+            }
+        })
+    }
+}
+
+impl Drop for VisitingMap {
+    fn drop(&mut self) {
+        READING.with_borrow_mut(|reading| {
+            reading.maps.pop();
+            if reading.maps.is_empty() {
+                reading.syntax = None;
+                reading.guessed.clear();
+            }
+        })
+    }
+}
 
 /// Error type for deserialization
 #[derive(Debug, thiserror::Error)]
@@ -25,6 +119,23 @@ pub enum Error {
     MissingField(String),
     #[error("invalid type: expected {expected}, got '{got}'")]
     InvalidType { expected: &'static str, got: String },
+    #[error("empty cell, which isn't allowed by the config")]
+    EmptyCell,
+    #[error("no variant of the enum at '{path}' has any data")]
+    NoVariant { path: String },
+    #[error("several variants of the enum at '{path}' have data: {variants:?}")]
+    SeveralVariants { path: String, variants: Vec<String> },
+    #[error("invalid {encoding:?} bytes: '{got}'")]
+    InvalidBytes {
+        encoding: BytesEncoding,
+        got: String,
+    },
+    #[error("invalid JSON in the cell of '{path}'")]
+    Json {
+        path: String,
+        #[source]
+        source: serde_json::Error,
+    },
 }
 
 impl de::Error for Error {
@@ -35,98 +146,354 @@ impl de::Error for Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
-/// Deserializer for a flattened map of string keys to string values.
+/// Deserializer for a single csv record with flattened headers.
 ///
-/// This is the main entry point - it deserializes nested structures from
-/// a flat map by looking up keys with the appropriate prefix.
+/// This is the main entry point - it deserializes nested structures by walking
+/// the [`HeaderTree`] and reading the cells of the current record.
+#[derive(Clone, Copy)]
 pub struct FlattenedMapDeserializer<'de> {
-    /// The flattened map data
-    data: &'de IndexMap<String, String>,
-    /// Current path prefix (for nested access)
-    prefix: Cow<'de, str>,
+    /// Headers parsed into paths
+    tree: &'de HeaderTree,
+    /// Path syntax used by the headers
+    config: &'de FlattenConfig,
+    /// Cells of the current record
+    record: &'de StringRecord,
+    /// Current node (for nested access)
+    node: NodeId,
+    /// struct fields without any column which get filled, see [`FilledFields`]
+    missing: Option<&'de FilledFields>,
+    /// cells read as text instead of their guessed type, see
+    /// [`FlattenedMapDeserializer::deserialize_retrying`]
+    text: Option<&'de TextCells>,
+    /// the current node is the content of a `Some`, so its presence marker is no field of it
+    in_option: bool,
+}
+
+/// Cells of a record read as text, and the ones guessed as something else
+#[derive(Debug, Default)]
+struct TextCells {
+    columns: BTreeSet<usize>,
+    /// columns visited as something other than text, with what they were visited as
+    guessed: RefCell<Vec<(usize, Unexpected<'static>)>>,
 }
 
 impl<'de> FlattenedMapDeserializer<'de> {
-    pub fn new(data: &'de IndexMap<String, String>) -> Self {
+    pub fn new(
+        tree: &'de HeaderTree,
+        config: &'de FlattenConfig,
+        record: &'de StringRecord,
+    ) -> Self {
         Self {
-            data,
-            prefix: Cow::Borrowed(""),
+            tree,
+            config,
+            record,
+            node: HeaderTree::ROOT,
+            missing: None,
+            text: None,
+            in_option: false,
         }
     }
 
-    /// Get the direct child field names at the current prefix level
-    fn child_fields(&self) -> Vec<&'de str> {
-        let mut fields: Vec<&str> = Vec::new();
-        let prefix_len = if self.prefix.is_empty() {
-            0
-        } else {
-            self.prefix.len() + JOIN_TAG.len()
-        };
+    /// Fills struct fields the headers have no column for, see [`FilledFields`]
+    pub fn with_filled_fields(self, missing: &'de FilledFields) -> Self {
+        Self {
+            missing: Some(missing),
+            ..self
+        }
+    }
 
-        for key in self.data.keys() {
-            let relevant = if self.prefix.is_empty() {
-                Some(key.as_str())
-            } else if key.starts_with(self.prefix.as_ref())
-                && key[self.prefix.len()..].starts_with(JOIN_TAG)
-            {
-                Some(&key[prefix_len..])
-            } else {
-                None
+    /// Deserializes `T`, reading cells as text where `T` rejected the type guessed for them.
+    ///
+    /// Once serde has buffered a guessed cell (internally tagged and untagged enums,
+    /// `#[serde(flatten)]`) the guess can't be taken back, so e.g. a `String` field holding
+    /// `42` gets an integer. When that fails, a guessed cell is read as text and the record is
+    /// read again, up to [`FlattenConfig::text_retries`] times. The cells the error message
+    /// mentions are tried first, the others after them. A cell the type rejects as text gets
+    /// its guess back, and so do the ones which turn out not to need text.
+    pub fn deserialize_retrying<T: DeserializeOwned>(self) -> Result<T> {
+        if self.config.text_retries == 0 {
+            return T::deserialize(self);
+        }
+        let read = |columns: &BTreeSet<usize>| {
+            let text = TextCells {
+                columns: columns.clone(),
+                guessed: Default::default(),
             };
-
-            if let Some(rest) = relevant {
-                // Get the first segment of the remaining path
-                let field = rest.split(JOIN_TAG).next().unwrap_or(rest);
-                if !field.is_empty() && !fields.contains(&field) {
-                    fields.push(field);
+            let result = T::deserialize(FlattenedMapDeserializer {
+                text: Some(&text),
+                ..self
+            });
+            (result, text.guessed.into_inner())
+        };
+        let (error, mut guessed) = match read(&BTreeSet::new()) {
+            (Ok(value), _) => return Ok(value),
+            (Err(error), guessed) => (error, guessed),
+        };
+        let mut retries = self.config.text_retries;
+        let mut retry = |columns: &BTreeSet<usize>| {
+            retries = retries.checked_sub(1)?;
+            Some(read(columns))
+        };
+        let (mut text, mut kept) = (BTreeSet::new(), BTreeSet::new());
+        let mut message = error.to_string();
+        let (mut value, text) = loop {
+            let rejected = text
+                .iter()
+                .copied()
+                .find(|column| message.contains(&Unexpected::Str(self.cell(*column)).to_string()));
+            match rejected {
+                Some(column) => {
+                    text.remove(&column);
+                    kept.insert(column);
+                }
+                None => {
+                    let left = || {
+                        guessed
+                            .iter()
+                            .filter(|(column, _)| !text.contains(column) && !kept.contains(column))
+                    };
+                    match left()
+                        .find(|(_, unexpected)| message.contains(&unexpected.to_string()))
+                        .or_else(|| left().next())
+                    {
+                        Some((column, _)) => text.insert(*column),
+                        None => return Err(error),
+                    };
+                }
+            }
+            match retry(&text) {
+                Some((Ok(value), _)) => break (value, text),
+                Some((Err(error), now_guessed)) => {
+                    message = error.to_string();
+                    guessed = now_guessed;
                 }
+                None => return Err(error),
+            }
+        };
+        let mut needed = text.clone();
+        for column in text {
+            let fewer = needed.iter().copied().filter(|c| *c != column).collect();
+            if let Some((Ok(fewer_value), _)) = retry(&fewer) {
+                (value, needed) = (fewer_value, fewer);
             }
         }
-        fields
+        Ok(value)
     }
 
-    /// Check if this is a leaf value (exact key match)
-    fn get_leaf_value(&self) -> Option<&'de str> {
-        if self.prefix.is_empty() {
-            None
-        } else {
-            self.data.get(self.prefix.as_ref()).map(|s| s.as_str())
+    fn child(&self, node: NodeId) -> Self {
+        Self {
+            node,
+            in_option: false,
+            ..*self
         }
     }
 
-    /// Check if this prefix represents an array (has idx-N children)
-    fn is_array(&self) -> bool {
-        self.child_fields().iter().any(|f| f.starts_with(ARR_PFX))
+    fn current(&self) -> &'de Node {
+        self.tree.node(self.node)
     }
 
-    /// Get array indices at current prefix
-    fn array_indices(&self) -> Vec<usize> {
-        let mut indices: Vec<usize> = self
-            .child_fields()
+    fn path(&self) -> String {
+        self.current().path.clone()
+    }
+
+    fn cell(&self, column: usize) -> &'de str {
+        self.record.get(column).unwrap_or_default()
+    }
+
+    /// Direct children of the current node, without the presence marker of an `Option`'s
+    /// content (see [`FlattenConfig::presence_marker`])
+    fn children(&self) -> impl Iterator<Item = (&'de Segment<'static>, NodeId)> + '_ {
+        self.current()
+            .children
             .iter()
-            .filter_map(|f| f.strip_prefix(ARR_PFX)?.parse().ok())
+            .filter(|(segment, _)| !(self.in_option && self.is_presence_marker(segment)))
+            .map(|(segment, node)| (segment, *node))
+    }
+
+    fn is_presence_marker(&self, segment: &Segment<'_>) -> bool {
+        match (segment, &self.config.presence_marker) {
+            (Segment::Field(field), Some(marker)) => field == marker,
+            _ => false,
+        }
+    }
+
+    /// Cell of the presence marker of the current node, if it has one
+    fn presence_cell(&self) -> Option<&'de str> {
+        self.current()
+            .children
+            .iter()
+            .find(|(segment, _)| self.is_presence_marker(segment))
+            .and_then(|(_, node)| self.child(*node).get_leaf_value())
+    }
+
+    /// Get the direct children at the current level
+    fn child_fields(&self) -> Vec<(&'de Segment<'static>, NodeId)> {
+        self.children().collect()
+    }
+
+    /// Check if this is a leaf value (exact key match)
+    fn get_leaf_value(&self) -> Option<&'de str> {
+        self.current().column.map(|column| self.cell(column))
+    }
+
+    /// Check if this node represents an array (has idx-N children)
+    fn is_array(&self) -> bool {
+        self.children()
+            .any(|(segment, _)| matches!(segment, Segment::Idx(_)))
+    }
+
+    /// Get array elements at current node, sorted by their index
+    fn array_indices(&self) -> Vec<NodeId> {
+        let mut indices: Vec<(usize, NodeId)> = self
+            .children()
+            .filter_map(|(segment, node)| match segment {
+                Segment::Idx(idx) => Some((*idx, node)),
+                Segment::Field(_) => None,
+            })
             .collect();
         indices.sort();
-        indices
+        indices.into_iter().map(|(_, node)| node).collect()
     }
 
-    /// Check if there are any non-empty values under the current prefix.
-    /// Used to determine if an Option<Struct> should be Some or None.
-    fn has_non_empty_descendants(&self) -> bool {
-        for (key, value) in self.data.iter() {
-            let matches = if self.prefix.is_empty() {
-                true
-            } else {
-                key == self.prefix.as_ref()
-                    || (key.starts_with(self.prefix.as_ref())
-                        && key[self.prefix.len()..].starts_with(JOIN_TAG))
-            };
+    /// A container kept as JSON text in a single cell (see [`FlattenConfig::is_opaque`]), or
+    /// an empty one written as `[]` / `{}` (see [`FlattenConfig::empty_markers`]). Other cells
+    /// are text, even when they start with a bracket.
+    fn json_cell(&self) -> Option<&'de str> {
+        match self.children().next().is_none() {
+            true => self.get_leaf_value().filter(|cell| match self.is_opaque() {
+                true => !cell.is_empty() && !self.config.is_null(cell),
+                false => self.config.empty_markers && matches!(*cell, "[]" | "{}"),
+            }),
+            false => None,
+        }
+    }
 
-            if matches && !value.is_empty() {
-                return true;
-            }
+    /// The cell of the current node is read as text, see [`FlattenedMapDeserializer::deserialize_retrying`]
+    fn is_text(&self) -> bool {
+        self.text
+            .zip(self.current().column)
+            .is_some_and(|(text, column)| text.columns.contains(&column))
+    }
+
+    /// Remembers the cell of the current node was visited as `unexpected` instead of text
+    fn guessed(&self, unexpected: Unexpected<'static>) {
+        if let Some((text, column)) = self.text.zip(self.current().column) {
+            text.guessed.borrow_mut().push((column, unexpected));
+        }
+    }
+
+    fn is_opaque(&self) -> bool {
+        (self.config.max_depth.is_some() || !self.config.opaque_paths.is_empty())
+            && self
+                .config
+                .is_opaque(&FieldPath::parse(&self.current().path, self.config))
+    }
+
+    fn parse_json<T>(
+        &self,
+        cell: &'de str,
+        deserialize: impl FnOnce(&mut serde_json::Deserializer<StrRead<'de>>) -> serde_json::Result<T>,
+    ) -> Result<T> {
+        let mut json = serde_json::Deserializer::from_str(cell);
+        deserialize(&mut json)
+            .and_then(|value| json.end().map(|()| value))
+            .map_err(|source| Error::Json {
+                path: self.path(),
+                source,
+            })
+    }
+
+    /// Visits `fields` as map entries, followed by the `missing` ones, see [`map_path`]
+    fn visit_entries<V>(
+        self,
+        fields: Vec<(&'de Segment<'static>, NodeId)>,
+        missing: &'de [(&'static str, String)],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let _visiting = VisitingMap::enter(self.path(), self.config);
+        visitor.visit_map(MapAccessor {
+            de: self,
+            fields: fields.into_iter(),
+            current_field: None,
+            missing: missing.iter(),
+        })
+    }
+
+    /// Visits every child as a map entry
+    fn visit_fields<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let fields = self.child_fields();
+        self.visit_entries(fields, &[], visitor)
+    }
+
+    /// Same as [`FlattenedMapDeserializer::visit_fields`], followed by the fields of the
+    /// struct which have no column but get filled (see [`FilledFields`])
+    fn visit_struct_fields<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let missing = self
+            .missing
+            .map_or(&[][..], |missing| missing.fields(&self.current().path));
+        let fields = self.child_fields();
+        self.visit_entries(fields, missing, visitor)
+    }
+
+    /// Trailing empty elements of the sequence at the current node are padding, see
+    /// [`FlattenConfig::trim_arrays`]
+    fn is_padded(&self) -> bool {
+        self.config.trim_arrays
+            || (!self.config.array_widths.is_empty()
+                && self
+                    .config
+                    .array_width(&FieldPath::parse(&self.current().path, self.config))
+                    .is_some())
+    }
+
+    /// Delimited cell encoding of the sequence at the current node, if any
+    fn multi_value(&self) -> Option<&'de MultiValue> {
+        match self.config.multi_value.as_ref()?.paths.is_empty() {
+            true => self.config.multi_value.as_ref(),
+            false => self
+                .config
+                .multi_value(&FieldPath::parse(&self.current().path, self.config)),
         }
-        false
+    }
+
+    /// Holds both a cell and nested columns, or both array elements and fields
+    fn is_mixed(&self) -> bool {
+        let children = self.child_fields();
+        self.current().column.is_some() && !children.is_empty()
+            || (self.is_array()
+                && children
+                    .iter()
+                    .any(|(segment, _)| matches!(segment, Segment::Field(_))))
+    }
+
+    /// Children with at least one non-empty cell
+    fn populated_fields(&self) -> Vec<(&'de Segment<'static>, NodeId)> {
+        self.child_fields()
+            .into_iter()
+            .filter(|(_, node)| self.child(*node).has_non_empty_descendants())
+            .collect()
+    }
+
+    fn has_non_empty_children(&self) -> bool {
+        !self.populated_fields().is_empty()
+    }
+
+    /// Check if there are any non-null values under the current node (see
+    /// [`FlattenConfig::is_null`]). Used to determine if an Option<Struct> should be Some or None.
+    fn has_non_empty_descendants(&self) -> bool {
+        self.current()
+            .columns
+            .iter()
+            .any(|column| !self.config.is_null(self.cell(*column)))
     }
 }
 
@@ -137,9 +504,48 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
     where
         V: Visitor<'de>,
     {
-        // Check if this is a leaf value first
-        if let Some(value) = self.get_leaf_value() {
-            return StrDeserializer::new(value).deserialize_any(visitor);
+        if let Some(cell) = self
+            .json_cell()
+            .filter(|cell| (cell.starts_with('{') || cell.starts_with('[')) && !self.is_text())
+        {
+            self.guessed(match cell.starts_with('{') {
+                true => Unexpected::Map,
+                false => Unexpected::Seq,
+            });
+            return self.parse_json(cell, |json| json.deserialize_any(visitor));
+        }
+
+        // Check if this is a leaf value first, a cell next to nested columns (the same path
+        // holding a scalar in one record and a container in another) wins unless it's empty
+        if let Some(value) = self
+            .get_leaf_value()
+            .filter(|value| !self.config.is_null(value) || !self.has_non_empty_children())
+        {
+            let cell = StrDeserializer::new(value, self.config);
+            if cell.guess().is_some() {
+                VisitingMap::guessed(&self.current().path, value);
+            }
+            return match self.text.map(|_| (self.is_text(), cell.guess())) {
+                Some((true, _)) => cell.visit_text(visitor),
+                Some((false, Some(unexpected))) => {
+                    self.guessed(unexpected);
+                    cell.deserialize_any(visitor)
+                }
+                Some((false, None)) | None => cell.deserialize_any(visitor),
+            };
+        }
+
+        // Columns of several shapes were merged here (e.g. variants of an untagged enum),
+        // only the ones holding data belong to this record
+        if self.is_mixed() {
+            let fields = self.populated_fields();
+            return match fields
+                .iter()
+                .all(|(segment, _)| matches!(segment, Segment::Idx(_)))
+            {
+                true => self.deserialize_seq(visitor),
+                false => self.visit_entries(fields, &[], visitor),
+            };
         }
 
         // Check if it's an array
@@ -148,7 +554,7 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
         }
 
         // Otherwise treat as a map/struct
-        self.deserialize_map(visitor)
+        self.visit_fields(visitor)
     }
 
     fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value>
@@ -156,9 +562,9 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
         V: Visitor<'de>,
     {
         if let Some(value) = self.get_leaf_value() {
-            StrDeserializer::new(value).deserialize_bool(visitor)
+            StrDeserializer::new(value, self.config).deserialize_bool(visitor)
         } else {
-            Err(Error::MissingField(self.prefix.into_owned()))
+            Err(Error::MissingField(self.path()))
         }
     }
 
@@ -167,9 +573,9 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
         V: Visitor<'de>,
     {
         if let Some(value) = self.get_leaf_value() {
-            StrDeserializer::new(value).deserialize_i8(visitor)
+            StrDeserializer::new(value, self.config).deserialize_i8(visitor)
         } else {
-            Err(Error::MissingField(self.prefix.into_owned()))
+            Err(Error::MissingField(self.path()))
         }
     }
 
@@ -178,9 +584,9 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
         V: Visitor<'de>,
     {
         if let Some(value) = self.get_leaf_value() {
-            StrDeserializer::new(value).deserialize_i16(visitor)
+            StrDeserializer::new(value, self.config).deserialize_i16(visitor)
         } else {
-            Err(Error::MissingField(self.prefix.into_owned()))
+            Err(Error::MissingField(self.path()))
         }
     }
 
@@ -189,9 +595,9 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
         V: Visitor<'de>,
     {
         if let Some(value) = self.get_leaf_value() {
-            StrDeserializer::new(value).deserialize_i32(visitor)
+            StrDeserializer::new(value, self.config).deserialize_i32(visitor)
         } else {
-            Err(Error::MissingField(self.prefix.into_owned()))
+            Err(Error::MissingField(self.path()))
         }
     }
 
@@ -200,9 +606,20 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
         V: Visitor<'de>,
     {
         if let Some(value) = self.get_leaf_value() {
-            StrDeserializer::new(value).deserialize_i64(visitor)
+            StrDeserializer::new(value, self.config).deserialize_i64(visitor)
+        } else {
+            Err(Error::MissingField(self.path()))
+        }
+    }
+
+    fn deserialize_i128<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        if let Some(value) = self.get_leaf_value() {
+            StrDeserializer::new(value, self.config).deserialize_i128(visitor)
         } else {
-            Err(Error::MissingField(self.prefix.into_owned()))
+            Err(Error::MissingField(self.path()))
         }
     }
 
@@ -211,9 +628,9 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
         V: Visitor<'de>,
     {
         if let Some(value) = self.get_leaf_value() {
-            StrDeserializer::new(value).deserialize_u8(visitor)
+            StrDeserializer::new(value, self.config).deserialize_u8(visitor)
         } else {
-            Err(Error::MissingField(self.prefix.into_owned()))
+            Err(Error::MissingField(self.path()))
         }
     }
 
@@ -222,9 +639,9 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
         V: Visitor<'de>,
     {
         if let Some(value) = self.get_leaf_value() {
-            StrDeserializer::new(value).deserialize_u16(visitor)
+            StrDeserializer::new(value, self.config).deserialize_u16(visitor)
         } else {
-            Err(Error::MissingField(self.prefix.into_owned()))
+            Err(Error::MissingField(self.path()))
         }
     }
 
@@ -233,9 +650,9 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
         V: Visitor<'de>,
     {
         if let Some(value) = self.get_leaf_value() {
-            StrDeserializer::new(value).deserialize_u32(visitor)
+            StrDeserializer::new(value, self.config).deserialize_u32(visitor)
         } else {
-            Err(Error::MissingField(self.prefix.into_owned()))
+            Err(Error::MissingField(self.path()))
         }
     }
 
@@ -244,9 +661,20 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
         V: Visitor<'de>,
     {
         if let Some(value) = self.get_leaf_value() {
-            StrDeserializer::new(value).deserialize_u64(visitor)
+            StrDeserializer::new(value, self.config).deserialize_u64(visitor)
         } else {
-            Err(Error::MissingField(self.prefix.into_owned()))
+            Err(Error::MissingField(self.path()))
+        }
+    }
+
+    fn deserialize_u128<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        if let Some(value) = self.get_leaf_value() {
+            StrDeserializer::new(value, self.config).deserialize_u128(visitor)
+        } else {
+            Err(Error::MissingField(self.path()))
         }
     }
 
@@ -255,9 +683,9 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
         V: Visitor<'de>,
     {
         if let Some(value) = self.get_leaf_value() {
-            StrDeserializer::new(value).deserialize_f32(visitor)
+            StrDeserializer::new(value, self.config).deserialize_f32(visitor)
         } else {
-            Err(Error::MissingField(self.prefix.into_owned()))
+            Err(Error::MissingField(self.path()))
         }
     }
 
@@ -266,9 +694,9 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
         V: Visitor<'de>,
     {
         if let Some(value) = self.get_leaf_value() {
-            StrDeserializer::new(value).deserialize_f64(visitor)
+            StrDeserializer::new(value, self.config).deserialize_f64(visitor)
         } else {
-            Err(Error::MissingField(self.prefix.into_owned()))
+            Err(Error::MissingField(self.path()))
         }
     }
 
@@ -277,9 +705,9 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
         V: Visitor<'de>,
     {
         if let Some(value) = self.get_leaf_value() {
-            StrDeserializer::new(value).deserialize_char(visitor)
+            StrDeserializer::new(value, self.config).deserialize_char(visitor)
         } else {
-            Err(Error::MissingField(self.prefix.into_owned()))
+            Err(Error::MissingField(self.path()))
         }
     }
 
@@ -288,9 +716,9 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
         V: Visitor<'de>,
     {
         if let Some(value) = self.get_leaf_value() {
-            visitor.visit_borrowed_str(value)
+            StrDeserializer::new(value, self.config).visit_text(visitor)
         } else {
-            Err(Error::MissingField(self.prefix.into_owned()))
+            Err(Error::MissingField(self.path()))
         }
     }
 
@@ -299,9 +727,9 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
         V: Visitor<'de>,
     {
         if let Some(value) = self.get_leaf_value() {
-            visitor.visit_borrowed_str(value)
+            StrDeserializer::new(value, self.config).visit_text(visitor)
         } else {
-            Err(Error::MissingField(self.prefix.into_owned()))
+            Err(Error::MissingField(self.path()))
         }
     }
 
@@ -310,9 +738,12 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
         V: Visitor<'de>,
     {
         if let Some(value) = self.get_leaf_value() {
-            visitor.visit_borrowed_bytes(value.as_bytes())
+            StrDeserializer::new(value, self.config).deserialize_bytes(visitor)
+        } else if self.is_array() {
+            // written as an array of numbers, see [`BytesEncoding::Array`]
+            self.deserialize_seq(visitor)
         } else {
-            Err(Error::MissingField(self.prefix.into_owned()))
+            Err(Error::MissingField(self.path()))
         }
     }
 
@@ -327,12 +758,20 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
     where
         V: Visitor<'de>,
     {
-        // In CSV, empty strings represent null/None values.
+        // Null cells are the `null_token` or, unless `empty_cells` says otherwise, empty ones.
         // For Option<T>, we need to check if there's any actual data:
-        // - For leaf values: non-empty string means Some
-        // - For nested structs: at least one non-empty descendant means Some
-        if self.has_non_empty_descendants() {
-            visitor.visit_some(self)
+        // - For leaf values: a non-null cell means Some
+        // - For nested structs: at least one non-null descendant means Some
+        // the presence marker written for `Some` containers decides, when there is one
+        let present = match self.presence_cell() {
+            Some(cell) => cell == "true",
+            None => self.has_non_empty_descendants(),
+        };
+        if present {
+            visitor.visit_some(Self {
+                in_option: true,
+                ..self
+            })
         } else {
             visitor.visit_none()
         }
@@ -363,56 +802,90 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
     where
         V: Visitor<'de>,
     {
-        let indices = self.array_indices();
+        if let Some(cell) = self
+            .json_cell()
+            .filter(|_| self.multi_value().is_none() || self.is_opaque())
+        {
+            return self.parse_json(cell, |json| json.deserialize_seq(visitor));
+        }
+        if let Some((cell, multi_value)) = self.get_leaf_value().zip(self.multi_value()) {
+            return visitor.visit_seq(CellsAccessor {
+                cells: multi_value
+                    .split(cell)
+                    .into_iter()
+                    .map(|cell| StrDeserializer::new(cell, self.config)),
+            });
+        }
+        // trailing elements with nothing but empty cells are padding, not values
+        let mut indices = self.array_indices();
+        while self.is_padded()
+            && indices
+                .last()
+                .is_some_and(|node| !self.child(*node).has_non_empty_descendants())
+        {
+            indices.pop();
+        }
         visitor.visit_seq(SeqAccessor {
-            data: self.data,
-            prefix: self.prefix,
+            de: self,
             indices: indices.into_iter(),
         })
     }
 
-    fn deserialize_tuple<V>(self, _len: usize, visitor: V) -> Result<V::Value>
+    fn deserialize_tuple<V>(self, len: usize, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        self.deserialize_seq(visitor)
+        if let Some(cell) = self.json_cell() {
+            return self.parse_json(cell, |json| json.deserialize_tuple(len, visitor));
+        }
+        let indices = self.array_indices();
+        visitor.visit_seq(SeqAccessor {
+            de: self,
+            indices: indices.into_iter(),
+        })
     }
 
     fn deserialize_tuple_struct<V>(
         self,
         _name: &'static str,
-        _len: usize,
+        len: usize,
         visitor: V,
     ) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        self.deserialize_seq(visitor)
+        self.deserialize_tuple(len, visitor)
     }
 
     fn deserialize_map<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        let fields = self.child_fields();
-        visitor.visit_map(MapAccessor {
-            data: self.data,
-            prefix: self.prefix,
-            fields: fields.into_iter(),
-            current_field: None,
-        })
+        if let Some(cell) = self.json_cell() {
+            return self.parse_json(cell, |json| json.deserialize_map(visitor));
+        }
+        // entries with nothing but empty cells only exist in other records
+        let fields = self
+            .child_fields()
+            .into_iter()
+            .filter(|(_, node)| self.child(*node).has_non_empty_descendants())
+            .collect::<Vec<_>>();
+        self.visit_entries(fields, &[], visitor)
     }
 
     fn deserialize_struct<V>(
         self,
-        _name: &'static str,
-        _fields: &'static [&'static str],
+        name: &'static str,
+        fields: &'static [&'static str],
         visitor: V,
     ) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        self.deserialize_map(visitor)
+        if let Some(cell) = self.json_cell() {
+            return self.parse_json(cell, |json| json.deserialize_struct(name, fields, visitor));
+        }
+        self.visit_struct_fields(visitor)
     }
 
     fn deserialize_enum<V>(
@@ -424,25 +897,40 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
     where
         V: Visitor<'de>,
     {
-        // For simple enums, treat the leaf value as a unit variant name
-        if let Some(value) = self.get_leaf_value() {
-            visitor.visit_enum(value.into_deserializer())
-        } else {
-            // For complex enums with data, treat child fields as variant name -> data
-            let fields = self.child_fields();
-            if fields.len() == 1 {
-                visitor.visit_enum(EnumAccessor {
-                    data: self.data,
-                    prefix: self.prefix,
-                    variant: fields[0],
-                })
-            } else {
-                Err(Error::Custom(format!(
-                    "expected enum at '{}', found {} fields",
-                    self.prefix,
-                    fields.len()
-                )))
-            }
+        // Columns of every variant may be present, only the variant holding data is used:
+        // a unit variant name in the enum's own cell, or the cells under the variant's path
+        // (a lone variant without a unit cell is used even if its cells are all empty)
+        let unit = self.get_leaf_value();
+        let fields = self.child_fields();
+        let populated = self.populated_fields();
+        match (
+            unit.filter(|value| !self.config.is_null(value)),
+            &populated[..],
+        ) {
+            (Some(value), []) => visitor.visit_enum(value.into_deserializer()),
+            (None, [(variant, node)]) => visitor.visit_enum(EnumAccessor {
+                variant,
+                de: self.child(*node),
+            }),
+            (None, []) => match (unit, &fields[..]) {
+                (None, [(variant, node)]) => visitor.visit_enum(EnumAccessor {
+                    variant,
+                    de: self.child(*node),
+                }),
+                _ => Err(Error::NoVariant { path: self.path() }),
+            },
+            (unit, populated) => Err(Error::SeveralVariants {
+                path: self.path(),
+                variants: unit
+                    .map(str::to_owned)
+                    .into_iter()
+                    .chain(
+                        populated
+                            .iter()
+                            .map(|(variant, _)| variant.render(self.config)),
+                    )
+                    .collect(),
+            }),
         }
     }
 
@@ -463,13 +951,21 @@ impl<'de> de::Deserializer<'de> for FlattenedMapDeserializer<'de> {
 
 /// MapAccess implementation for iterating over struct fields
 struct MapAccessor<'de, I> {
-    data: &'de IndexMap<String, String>,
-    prefix: Cow<'de, str>,
+    de: FlattenedMapDeserializer<'de>,
     fields: I,
-    current_field: Option<&'de str>,
+    current_field: Option<Field<'de>>,
+    /// paths of the struct fields filled by [`MissingDeserializer`], after the other fields
+    missing: std::slice::Iter<'de, (&'static str, String)>,
+}
+
+enum Field<'de> {
+    Node(NodeId),
+    Missing(&'de str),
 }
 
-impl<'de, I: Iterator<Item = &'de str>> MapAccess<'de> for MapAccessor<'de, I> {
+impl<'de, I: Iterator<Item = (&'de Segment<'static>, NodeId)>> MapAccess<'de>
+    for MapAccessor<'de, I>
+{
     type Error = Error;
 
     fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
@@ -477,11 +973,27 @@ impl<'de, I: Iterator<Item = &'de str>> MapAccess<'de> for MapAccessor<'de, I> {
         K: DeserializeSeed<'de>,
     {
         match self.fields.next() {
-            Some(field) => {
-                self.current_field = Some(field);
-                seed.deserialize(field.into_deserializer()).map(Some)
+            Some((segment, node)) => {
+                self.current_field = Some(Field::Node(node));
+                match segment {
+                    Segment::Field(key) => {
+                        seed.deserialize(StrDeserializer::key(key.as_ref(), self.de.config))
+                    }
+                    Segment::Idx(_) => seed.deserialize(StrDeserializer::key(
+                        segment.render(self.de.config),
+                        self.de.config,
+                    )),
+                }
+                .map(Some)
             }
-            None => Ok(None),
+            None => match self.missing.next() {
+                Some((key, path)) => {
+                    self.current_field = Some(Field::Missing(path.as_str()));
+                    seed.deserialize(StrDeserializer::key(*key, self.de.config))
+                        .map(Some)
+                }
+                None => Ok(None),
+            },
         }
     }
 
@@ -489,32 +1001,31 @@ impl<'de, I: Iterator<Item = &'de str>> MapAccess<'de> for MapAccessor<'de, I> {
     where
         V: DeserializeSeed<'de>,
     {
-        let field = self
+        match self
             .current_field
             .take()
-            .ok_or_else(|| Error::Custom("next_value_seed called before next_key_seed".into()))?;
-
-        let new_prefix = if self.prefix.is_empty() {
-            Cow::Owned(field.to_string())
-        } else {
-            Cow::Owned(format!("{}{JOIN_TAG}{field}", self.prefix))
-        };
-
-        seed.deserialize(FlattenedMapDeserializer {
-            data: self.data,
-            prefix: new_prefix,
-        })
+            .ok_or_else(|| Error::Custom("next_value_seed called before next_key_seed".into()))?
+        {
+            Field::Node(node) => seed.deserialize(self.de.child(node)),
+            Field::Missing(path) => match self.de.missing {
+                Some(missing) => seed.deserialize(MissingDeserializer {
+                    path: path.into(),
+                    missing,
+                    config: self.de.config,
+                }),
+                None => Err(Error::MissingField(path.to_string())),
+            },
+        }
     }
 }
 
 /// SeqAccess implementation for iterating over array elements
 struct SeqAccessor<'de, I> {
-    data: &'de IndexMap<String, String>,
-    prefix: Cow<'de, str>,
+    de: FlattenedMapDeserializer<'de>,
     indices: I,
 }
 
-impl<'de, I: Iterator<Item = usize>> SeqAccess<'de> for SeqAccessor<'de, I> {
+impl<'de, I: Iterator<Item = NodeId>> SeqAccess<'de> for SeqAccessor<'de, I> {
     type Error = Error;
 
     fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
@@ -522,30 +1033,36 @@ impl<'de, I: Iterator<Item = usize>> SeqAccess<'de> for SeqAccessor<'de, I> {
         T: DeserializeSeed<'de>,
     {
         match self.indices.next() {
-            Some(idx) => {
-                let field = format!("{ARR_PFX}{idx}");
-                let new_prefix = if self.prefix.is_empty() {
-                    Cow::Owned(field)
-                } else {
-                    Cow::Owned(format!("{}{JOIN_TAG}{field}", self.prefix))
-                };
-
-                seed.deserialize(FlattenedMapDeserializer {
-                    data: self.data,
-                    prefix: new_prefix,
-                })
-                .map(Some)
-            }
+            Some(node) => seed.deserialize(self.de.child(node)).map(Some),
             None => Ok(None),
         }
     }
 }
 
+/// SeqAccess implementation for the values of a single delimited cell (or tuple key)
+struct CellsAccessor<I> {
+    cells: I,
+}
+
+impl<'de, I: Iterator<Item = StrDeserializer<'de>>> SeqAccess<'de> for CellsAccessor<I> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        self.cells
+            .next()
+            .map(|cell| seed.deserialize(cell))
+            .transpose()
+    }
+}
+
 /// EnumAccess implementation for deserializing enums
 struct EnumAccessor<'de> {
-    data: &'de IndexMap<String, String>,
-    prefix: Cow<'de, str>,
-    variant: &'de str,
+    /// already pointing at the variant's node
+    de: FlattenedMapDeserializer<'de>,
+    variant: &'de Segment<'static>,
 }
 
 impl<'de> de::EnumAccess<'de> for EnumAccessor<'de> {
@@ -556,24 +1073,14 @@ impl<'de> de::EnumAccess<'de> for EnumAccessor<'de> {
     where
         V: DeserializeSeed<'de>,
     {
-        let variant_de = self.variant.into_deserializer();
-        let variant = seed.deserialize(variant_de)?;
-
-        let new_prefix = if self.prefix.is_empty() {
-            Cow::Owned(self.variant.to_string())
-        } else {
-            Cow::Owned(format!("{}{JOIN_TAG}{}", self.prefix, self.variant))
+        let variant = match self.variant {
+            Segment::Field(name) => seed.deserialize(name.as_ref().into_deserializer())?,
+            Segment::Idx(_) => {
+                seed.deserialize(self.variant.render(self.de.config).into_deserializer())?
+            }
         };
 
-        Ok((
-            variant,
-            VariantAccessor {
-                de: FlattenedMapDeserializer {
-                    data: self.data,
-                    prefix: new_prefix,
-                },
-            },
-        ))
+        Ok((variant, VariantAccessor { de: self.de }))
     }
 }
 
@@ -610,16 +1117,298 @@ impl<'de> de::VariantAccess<'de> for VariantAccessor<'de> {
     }
 }
 
+/// Deserializer for a struct field without any column, see [`FilledFields`]
+struct MissingDeserializer<'de> {
+    /// flattened path of the field
+    path: Cow<'de, str>,
+    missing: &'de FilledFields,
+    config: &'de FlattenConfig,
+}
+
+impl<'de> MissingDeserializer<'de> {
+    fn fallback(&self) -> Option<StrDeserializer<'de>> {
+        self.missing
+            .fallback(&self.path)
+            .map(|cell| StrDeserializer::new(cell, self.config))
+    }
+
+    fn nested(&self, segment: Segment<'_>) -> Self {
+        Self {
+            path: self
+                .config
+                .join(&self.path, &segment.render(self.config))
+                .into(),
+            ..*self
+        }
+    }
+}
+
+/// leaves are only filled by a fallback, see [`FilledFields`]
+macro_rules! fill_missing {
+    ($($method:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+                match self.fallback() {
+                    Some(fallback) => fallback.$method(visitor),
+                    None => Err(Error::MissingField(self.path.into_owned())),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for MissingDeserializer<'de> {
+    type Error = Error;
+
+    fill_missing! {
+        deserialize_bool,
+        deserialize_i8,
+        deserialize_i16,
+        deserialize_i32,
+        deserialize_i64,
+        deserialize_i128,
+        deserialize_u8,
+        deserialize_u16,
+        deserialize_u32,
+        deserialize_u64,
+        deserialize_u128,
+        deserialize_f32,
+        deserialize_f64,
+        deserialize_char,
+        deserialize_str,
+        deserialize_string,
+        deserialize_bytes,
+        deserialize_byte_buf,
+        deserialize_any,
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.fallback() {
+            Some(fallback) => fallback.deserialize_option(visitor),
+            None => visitor.visit_none(),
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.fallback() {
+            Some(fallback) => fallback.deserialize_seq(visitor),
+            None => visitor.visit_seq(CellsAccessor {
+                cells: std::iter::empty(),
+            }),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        visitor.visit_seq(MissingAccessor {
+            fields: (0..len)
+                .map(|idx| (None, self.nested(Segment::Idx(idx))))
+                .collect::<Vec<_>>()
+                .into_iter(),
+            current: None,
+        })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_map(MissingAccessor {
+            fields: Vec::new().into_iter(),
+            current: None,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_map(MissingAccessor {
+            fields: self.missing.fields(&self.path).iter().map(|(field, path)| {
+                (
+                    Some(*field),
+                    MissingDeserializer {
+                        path: path.as_str().into(),
+                        ..self
+                    },
+                )
+            }),
+            current: None,
+        })
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self.fallback() {
+            Some(fallback) => fallback.deserialize_enum(name, variants, visitor),
+            None => Err(Error::MissingField(self.path.into_owned())),
+        }
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
+        Err(Error::MissingField(self.path.into_owned()))
+    }
+}
+
+/// Fields (named) or tuple elements of a value without any column
+struct MissingAccessor<'de, I> {
+    fields: I,
+    current: Option<MissingDeserializer<'de>>,
+}
+
+impl<'de, I> MapAccess<'de> for MissingAccessor<'de, I>
+where
+    I: Iterator<Item = (Option<&'static str>, MissingDeserializer<'de>)>,
+{
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        match self.fields.next() {
+            Some((field, de)) => {
+                self.current = Some(de);
+                seed.deserialize(field.unwrap_or_default().into_deserializer())
+                    .map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        self.current
+            .take()
+            .ok_or_else(|| Error::Custom("next_value_seed called before next_key_seed".into()))
+            .and_then(|de| seed.deserialize(de))
+    }
+}
+
+impl<'de, I> SeqAccess<'de> for MissingAccessor<'de, I>
+where
+    I: Iterator<Item = (Option<&'static str>, MissingDeserializer<'de>)>,
+{
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        self.fields
+            .next()
+            .map(|(_, de)| seed.deserialize(de))
+            .transpose()
+    }
+}
+
 /// Deserializer for leaf string values.
 ///
 /// This handles converting raw strings to the requested type.
 struct StrDeserializer<'de> {
-    value: &'de str,
+    value: Cow<'de, str>,
+    config: &'de FlattenConfig,
+    /// a map key: never guessed, tuples are split at [`FlattenConfig::key_delimiter`]
+    key: bool,
 }
 
 impl<'de> StrDeserializer<'de> {
-    fn new(value: &'de str) -> Self {
-        Self { value }
+    fn new(value: impl Into<Cow<'de, str>>, config: &'de FlattenConfig) -> Self {
+        Self {
+            value: value.into(),
+            config,
+            key: false,
+        }
+    }
+
+    fn key(value: impl Into<Cow<'de, str>>, config: &'de FlattenConfig) -> Self {
+        Self {
+            key: true,
+            ..Self::new(value, config)
+        }
+    }
+
+    /// elements of a tuple key
+    fn key_parts<V>(self, len: usize, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let parts = MultiValue::new(self.config.key_delimiter).split(&self.value);
+        match parts.len() == len {
+            true => visitor.visit_seq(CellsAccessor {
+                cells: parts
+                    .into_iter()
+                    .map(|part| StrDeserializer::key(part.into_owned(), self.config))
+                    .collect::<Vec<_>>()
+                    .into_iter(),
+            }),
+            false => Err(Error::InvalidType {
+                expected: "tuple key",
+                got: self.value.to_string(),
+            }),
+        }
+    }
+
+    /// What [`deserialize_any`](de::Deserializer::deserialize_any) visits the cell as,
+    /// `None` for text
+    fn guess(&self) -> Option<Unexpected<'static>> {
+        match self.config.guess_types.guess(&self.value) {
+            _ if self.key => None,
+            _ if self.config.guess_types.null && self.config.is_null(&self.value) => {
+                Some(Unexpected::Unit)
+            }
+            // an empty cell which isn't null, see [`FlattenConfig::empty_cells`]
+            Guess::Null | Guess::Str => None,
+            Guess::Bool(v) => Some(Unexpected::Bool(v)),
+            Guess::I64(v) => Some(Unexpected::Signed(v)),
+            Guess::U64(v) => Some(Unexpected::Unsigned(v)),
+            Guess::F64(v) => Some(Unexpected::Float(v)),
+            #[cfg(feature = "arbitrary_precision")]
+            Guess::Number => Some(Unexpected::Other("number")),
+            #[cfg(not(feature = "arbitrary_precision"))]
+            Guess::Number => None,
+        }
+    }
+
+    fn visit_text<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        if self.value.is_empty() && self.config.empty_cells == EmptyCells::Error {
+            return Err(Error::EmptyCell);
+        }
+        match self.value {
+            Cow::Borrowed(value) => visitor.visit_borrowed_str(value),
+            Cow::Owned(value) => visitor.visit_string(value),
+        }
     }
 }
 
@@ -630,15 +1419,30 @@ impl<'de> de::Deserializer<'de> for StrDeserializer<'de> {
     where
         V: Visitor<'de>,
     {
-        // When type is unknown, return as string and let visitor decide
-        visitor.visit_borrowed_str(self.value)
+        // When type is unknown, guess it from the text
+        match self.guess() {
+            None => self.visit_text(visitor),
+            Some(Unexpected::Unit) => visitor.visit_unit(),
+            Some(Unexpected::Bool(v)) => visitor.visit_bool(v),
+            Some(Unexpected::Signed(v)) => visitor.visit_i64(v),
+            Some(Unexpected::Unsigned(v)) => visitor.visit_u64(v),
+            Some(Unexpected::Float(v)) => visitor.visit_f64(v),
+            // serde_json's representation of a number kept as text
+            #[cfg(feature = "arbitrary_precision")]
+            Some(_) => visitor.visit_map(de::value::MapDeserializer::new(std::iter::once((
+                crate::flatten_json_value::serializer::NUMBER_TOKEN,
+                self.value.as_ref(),
+            )))),
+            #[cfg(not(feature = "arbitrary_precision"))]
+            Some(_) => self.visit_text(visitor),
+        }
     }
 
     fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        match self.value {
+        match self.value.as_ref() {
             "true" => visitor.visit_bool(true),
             "false" => visitor.visit_bool(false),
             _ => Err(Error::InvalidType {
@@ -692,6 +1496,17 @@ impl<'de> de::Deserializer<'de> for StrDeserializer<'de> {
         visitor.visit_i64(n)
     }
 
+    fn deserialize_i128<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let n: i128 = self.value.parse().map_err(|_| Error::InvalidType {
+            expected: "i128",
+            got: self.value.to_string(),
+        })?;
+        visitor.visit_i128(n)
+    }
+
     fn deserialize_u8<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
@@ -736,14 +1551,28 @@ impl<'de> de::Deserializer<'de> for StrDeserializer<'de> {
         visitor.visit_u64(n)
     }
 
-    fn deserialize_f32<V>(self, visitor: V) -> Result<V::Value>
+    fn deserialize_u128<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        let n: f32 = self.value.parse().map_err(|_| Error::InvalidType {
-            expected: "f32",
+        let n: u128 = self.value.parse().map_err(|_| Error::InvalidType {
+            expected: "u128",
             got: self.value.to_string(),
         })?;
+        visitor.visit_u128(n)
+    }
+
+    fn deserialize_f32<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let n: f32 = match self.config.non_finite.parse(&self.value) {
+            Some(non_finite) => non_finite as f32,
+            None => self.value.parse().map_err(|_| Error::InvalidType {
+                expected: "f32",
+                got: self.value.to_string(),
+            })?,
+        };
         visitor.visit_f32(n)
     }
 
@@ -751,10 +1580,13 @@ impl<'de> de::Deserializer<'de> for StrDeserializer<'de> {
     where
         V: Visitor<'de>,
     {
-        let n: f64 = self.value.parse().map_err(|_| Error::InvalidType {
-            expected: "f64",
-            got: self.value.to_string(),
-        })?;
+        let n: f64 = match self.config.non_finite.parse(&self.value) {
+            Some(non_finite) => non_finite,
+            None => self.value.parse().map_err(|_| Error::InvalidType {
+                expected: "f64",
+                got: self.value.to_string(),
+            })?,
+        };
         visitor.visit_f64(n)
     }
 
@@ -776,21 +1608,33 @@ impl<'de> de::Deserializer<'de> for StrDeserializer<'de> {
     where
         V: Visitor<'de>,
     {
-        visitor.visit_borrowed_str(self.value)
+        self.visit_text(visitor)
     }
 
     fn deserialize_string<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        visitor.visit_borrowed_str(self.value)
+        self.visit_text(visitor)
     }
 
     fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        visitor.visit_borrowed_bytes(self.value.as_bytes())
+        match (self.config.bytes, self.value) {
+            (BytesEncoding::Array, Cow::Borrowed(value)) => {
+                visitor.visit_borrowed_bytes(value.as_bytes())
+            }
+            (BytesEncoding::Array, Cow::Owned(value)) => visitor.visit_byte_buf(value.into_bytes()),
+            (encoding, value) => match encoding.decode(&value) {
+                Some(bytes) => visitor.visit_byte_buf(bytes),
+                None => Err(Error::InvalidBytes {
+                    encoding,
+                    got: value.into_owned(),
+                }),
+            },
+        }
     }
 
     fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value>
@@ -804,7 +1648,7 @@ impl<'de> de::Deserializer<'de> for StrDeserializer<'de> {
     where
         V: Visitor<'de>,
     {
-        if self.value.is_empty() {
+        if self.config.is_null(&self.value) {
             visitor.visit_none()
         } else {
             visitor.visit_some(self)
@@ -842,29 +1686,35 @@ impl<'de> de::Deserializer<'de> for StrDeserializer<'de> {
         })
     }
 
-    fn deserialize_tuple<V>(self, _len: usize, _visitor: V) -> Result<V::Value>
+    fn deserialize_tuple<V>(self, len: usize, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        Err(Error::InvalidType {
-            expected: "tuple",
-            got: "string".to_string(),
-        })
+        match self.key {
+            true => self.key_parts(len, visitor),
+            false => Err(Error::InvalidType {
+                expected: "tuple",
+                got: "string".to_string(),
+            }),
+        }
     }
 
     fn deserialize_tuple_struct<V>(
         self,
         _name: &'static str,
-        _len: usize,
-        _visitor: V,
+        len: usize,
+        visitor: V,
     ) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        Err(Error::InvalidType {
-            expected: "tuple struct",
-            got: "string".to_string(),
-        })
+        match self.key {
+            true => self.key_parts(len, visitor),
+            false => Err(Error::InvalidType {
+                expected: "tuple struct",
+                got: "string".to_string(),
+            }),
+        }
     }
 
     fn deserialize_map<V>(self, _visitor: V) -> Result<V::Value>
@@ -908,7 +1758,7 @@ impl<'de> de::Deserializer<'de> for StrDeserializer<'de> {
     where
         V: Visitor<'de>,
     {
-        visitor.visit_borrowed_str(self.value)
+        self.visit_text(visitor)
     }
 
     fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value>
@@ -921,8 +1771,14 @@ impl<'de> de::Deserializer<'de> for StrDeserializer<'de> {
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-    use serde::Deserialize;
+    use {super::*, indexmap::IndexMap, serde::Deserialize};
+
+    fn header_tree_and_record(data: &IndexMap<String, String>) -> (HeaderTree, StringRecord) {
+        (
+            HeaderTree::new(data.keys().map(String::as_str), &FlattenConfig::DEFAULT),
+            data.values().collect(),
+        )
+    }
 
     #[test]
     fn test_simple_struct() {
@@ -936,7 +1792,9 @@ mod tests {
         data.insert("name".to_string(), "Alice".to_string());
         data.insert("age".to_string(), "30".to_string());
 
-        let de = FlattenedMapDeserializer::new(&data);
+        let (tree, record) = header_tree_and_record(&data);
+        let config = FlattenConfig::default();
+        let de = FlattenedMapDeserializer::new(&tree, &config, &record);
         let result: Simple = Simple::deserialize(de).unwrap();
 
         assert_eq!(
@@ -965,7 +1823,9 @@ mod tests {
         data.insert("inner__value".to_string(), "42".to_string());
         data.insert("label".to_string(), "test".to_string());
 
-        let de = FlattenedMapDeserializer::new(&data);
+        let (tree, record) = header_tree_and_record(&data);
+        let config = FlattenConfig::default();
+        let de = FlattenedMapDeserializer::new(&tree, &config, &record);
         let result: Outer = Outer::deserialize(de).unwrap();
 
         assert_eq!(
@@ -988,7 +1848,9 @@ mod tests {
         let mut data = IndexMap::new();
         data.insert("id".to_string(), "123".to_string());
 
-        let de = FlattenedMapDeserializer::new(&data);
+        let (tree, record) = header_tree_and_record(&data);
+        let config = FlattenConfig::default();
+        let de = FlattenedMapDeserializer::new(&tree, &config, &record);
         let result: Data = Data::deserialize(de).unwrap();
 
         assert_eq!(
@@ -1011,7 +1873,9 @@ mod tests {
         data.insert("name".to_string(), "Alice".to_string());
         data.insert("age".to_string(), "".to_string()); // empty = null in CSV
 
-        let de = FlattenedMapDeserializer::new(&data);
+        let (tree, record) = header_tree_and_record(&data);
+        let config = FlattenConfig::default();
+        let de = FlattenedMapDeserializer::new(&tree, &config, &record);
         let result: Data = Data::deserialize(de).unwrap();
 
         assert_eq!(
@@ -1035,7 +1899,9 @@ mod tests {
         data.insert("name".to_string(), "Bob".to_string());
         data.insert("age".to_string(), "25".to_string());
 
-        let de = FlattenedMapDeserializer::new(&data);
+        let (tree, record) = header_tree_and_record(&data);
+        let config = FlattenConfig::default();
+        let de = FlattenedMapDeserializer::new(&tree, &config, &record);
         let result: Data = Data::deserialize(de).unwrap();
 
         assert_eq!(
@@ -1059,7 +1925,9 @@ mod tests {
         data.insert("name".to_string(), "Charlie".to_string());
         // age key not present at all
 
-        let de = FlattenedMapDeserializer::new(&data);
+        let (tree, record) = header_tree_and_record(&data);
+        let config = FlattenConfig::default();
+        let de = FlattenedMapDeserializer::new(&tree, &config, &record);
         let result: Data = Data::deserialize(de).unwrap();
 
         assert_eq!(
@@ -1089,7 +1957,9 @@ mod tests {
         data.insert("label".to_string(), "test".to_string());
         data.insert("inner__value".to_string(), "42".to_string());
 
-        let de = FlattenedMapDeserializer::new(&data);
+        let (tree, record) = header_tree_and_record(&data);
+        let config = FlattenConfig::default();
+        let de = FlattenedMapDeserializer::new(&tree, &config, &record);
         let result: Outer = Outer::deserialize(de).unwrap();
 
         assert_eq!(
@@ -1104,7 +1974,9 @@ mod tests {
         let mut data = IndexMap::new();
         data.insert("label".to_string(), "test2".to_string());
 
-        let de = FlattenedMapDeserializer::new(&data);
+        let (tree, record) = header_tree_and_record(&data);
+        let config = FlattenConfig::default();
+        let de = FlattenedMapDeserializer::new(&tree, &config, &record);
         let result: Outer = Outer::deserialize(de).unwrap();
 
         assert_eq!(
@@ -1133,7 +2005,9 @@ mod tests {
         data.insert("price__amount".to_string(), "".to_string());
         data.insert("price__currency".to_string(), "".to_string());
 
-        let de = FlattenedMapDeserializer::new(&data);
+        let (tree, record) = header_tree_and_record(&data);
+        let config = FlattenConfig::default();
+        let de = FlattenedMapDeserializer::new(&tree, &config, &record);
         let result: Product = Product::deserialize(de).unwrap();
 
         assert_eq!(
@@ -1150,7 +2024,9 @@ mod tests {
         data.insert("price__amount".to_string(), "".to_string());
         data.insert("price__currency".to_string(), "USD".to_string());
 
-        let de = FlattenedMapDeserializer::new(&data);
+        let (tree, record) = header_tree_and_record(&data);
+        let config = FlattenConfig::default();
+        let de = FlattenedMapDeserializer::new(&tree, &config, &record);
         let result: Product = Product::deserialize(de).unwrap();
 
         assert_eq!(
@@ -1176,9 +2052,147 @@ mod tests {
         let mut data = IndexMap::new();
         data.insert("nickname".to_string(), "".to_string());
 
-        let de = FlattenedMapDeserializer::new(&data);
+        let (tree, record) = header_tree_and_record(&data);
+        let config = FlattenConfig::default();
+        let de = FlattenedMapDeserializer::new(&tree, &config, &record);
         let result: Data = Data::deserialize(de).unwrap();
 
         assert_eq!(result, Data { nickname: None });
     }
+
+    #[test]
+    fn test_trailing_empty_elements_are_trimmed() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Item {
+            id: u32,
+            label: Option<String>,
+        }
+
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Data {
+            items: Vec<Item>,
+            point: (u32, Option<u32>),
+        }
+
+        let mut data = IndexMap::new();
+        data.insert("items__idx-0__id".to_string(), "1".to_string());
+        data.insert("items__idx-0__label".to_string(), "".to_string());
+        data.insert("items__idx-1__id".to_string(), "".to_string());
+        data.insert("items__idx-1__label".to_string(), "".to_string());
+        data.insert("point__idx-0".to_string(), "3".to_string());
+        data.insert("point__idx-1".to_string(), "".to_string());
+
+        let (tree, record) = header_tree_and_record(&data);
+        let config = FlattenConfig::default().with_trimmed_arrays();
+        let de = FlattenedMapDeserializer::new(&tree, &config, &record);
+        let result: Data = Data::deserialize(de).unwrap();
+
+        assert_eq!(
+            result,
+            Data {
+                items: vec![Item { id: 1, label: None }],
+                point: (3, None),
+            }
+        );
+    }
+
+    #[test]
+    fn test_populated_enum_variant_is_chosen() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        enum Shape {
+            Empty,
+            Circle { radius: f64 },
+            Rect { w: u32, h: u32 },
+        }
+
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Data {
+            shapes: Vec<Shape>,
+            maybe: Option<Shape>,
+        }
+
+        let deserialize = |cells: [&str; 8]| {
+            let data = [
+                "shapes__idx-0",
+                "shapes__idx-0__Circle__radius",
+                "shapes__idx-0__Rect__w",
+                "shapes__idx-0__Rect__h",
+                "shapes__idx-1",
+                "shapes__idx-1__Circle__radius",
+                "maybe__Circle__radius",
+                "maybe__Rect__w",
+            ]
+            .into_iter()
+            .map(str::to_string)
+            .zip(cells.map(str::to_string))
+            .collect::<IndexMap<_, _>>();
+            let (tree, record) = header_tree_and_record(&data);
+            let config = FlattenConfig::default().with_trimmed_arrays();
+            Data::deserialize(FlattenedMapDeserializer::new(&tree, &config, &record))
+        };
+
+        assert_eq!(
+            deserialize(["", "", "1", "2", "Empty", "", "", ""]).unwrap(),
+            Data {
+                shapes: vec![Shape::Rect { w: 1, h: 2 }, Shape::Empty],
+                maybe: None,
+            }
+        );
+        assert_eq!(
+            deserialize(["", "1.5", "", "", "", "", "2.5", ""]).unwrap(),
+            Data {
+                shapes: vec![Shape::Circle { radius: 1.5 }],
+                maybe: Some(Shape::Circle { radius: 2.5 }),
+            }
+        );
+        assert!(matches!(
+            deserialize(["Empty", "1.5", "", "", "", "", "", ""]),
+            Err(Error::SeveralVariants { path, variants })
+                if path == "shapes__idx-0" && variants == ["Empty", "Circle"]
+        ));
+        assert!(matches!(
+            deserialize(["", "", "", "", "Empty", "", "", ""]),
+            Err(Error::NoVariant { path }) if path == "shapes__idx-0"
+        ));
+        assert!(matches!(
+            deserialize(["", "", "", "", "", "", "2.5", "3"]),
+            Err(Error::SeveralVariants { path, .. }) if path == "maybe"
+        ));
+    }
+
+    #[test]
+    fn test_map_path_is_reset_after_a_panic() {
+        struct Panics;
+
+        impl<'de> Deserialize<'de> for Panics {
+            fn deserialize<D: Deserializer<'de>>(_: D) -> std::result::Result<Self, D::Error> {
+                assert_eq!(map_path().as_deref(), Some("inner"));
+                panic!("deserializing")
+            }
+        }
+
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        struct Inner {
+            value: Panics,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        struct Data {
+            inner: Inner,
+        }
+
+        let mut data = IndexMap::new();
+        data.insert("inner__value".to_string(), "1".to_string());
+
+        let (tree, record) = header_tree_and_record(&data);
+        let config = FlattenConfig::default();
+        let caught = std::panic::catch_unwind(|| {
+            Data::deserialize(FlattenedMapDeserializer::new(&tree, &config, &record)).ok()
+        });
+
+        assert!(caught.is_err());
+        assert_eq!(map_path(), None);
+    }
 }
@@ -1,24 +1,38 @@
 use {
-    crate::FlattenedRef,
-    serde::{Serialize, ser::SerializeStruct},
-    std::{cell::RefCell, collections::HashMap},
+    crate::{
+        FlattenedRef,
+        flatten_json_value::{
+            FieldPath, FlattenConfig, flatten,
+            serializer::{FlatSerializer, FlatSink, Leaf},
+        },
+    },
+    serde::{
+        Serialize,
+        ser::{Error as _, SerializeMap},
+    },
 };
 
-#[derive(Debug, Default)]
-struct StaticLookup(HashMap<Box<str>, &'static str>);
-
-impl StaticLookup {
-    fn intern(&mut self, val: impl AsRef<str>) -> &'static str {
-        if !self.0.contains_key(val.as_ref()) {
-            self.0
-                .insert(Box::from(val.as_ref()), Box::leak(Box::from(val.as_ref())));
-        }
-        self.0.get(val.as_ref()).expect("checked above")
-    }
+/// Forwards every leaf as an entry of the outer map
+struct MapSink<M: SerializeMap> {
+    map: M,
+    /// the outer serializer's own error, handed back as is
+    error: Option<M::Error>,
 }
 
-thread_local! {
-    static STATIC_LOOKUP: RefCell<StaticLookup> = Default::default();
+impl<M: SerializeMap> FlatSink for MapSink<M> {
+    fn push(
+        &mut self,
+        _: &FieldPath<'_>,
+        key: &str,
+        leaf: Leaf<'_>,
+        _: &FlattenConfig,
+    ) -> Result<(), flatten::Error> {
+        self.map.serialize_entry(key, &leaf).map_err(|error| {
+            let message = error.to_string();
+            self.error = Some(error);
+            flatten::Error::Custom(message)
+        })
+    }
 }
 
 impl<T> Serialize for FlattenedRef<'_, T>
@@ -29,26 +43,14 @@ where
     where
         S: serde::Serializer,
     {
-        serde_json::to_value(self.0)
-            .map_err(serde::ser::Error::custom)
-            .map(crate::flatten_json_value::flatten::flattened)
-            .and_then({
-                move |v| {
-                    serializer
-                        .serialize_struct(std::any::type_name::<Self>(), v.len())
-                        .and_then(|mut serialize_struct| {
-                            v.into_iter()
-                                .try_for_each(|(k, v)| {
-                                    serialize_struct.serialize_field(
-                                        STATIC_LOOKUP.with_borrow_mut(|static_lookup| {
-                                            static_lookup.intern(k)
-                                        }),
-                                        &v,
-                                    )
-                                })
-                                .and_then(|()| serialize_struct.end())
-                        })
-                }
-            })
+        let config = FlattenConfig::DEFAULT;
+        let map = serializer.serialize_map(None)?;
+        let mut flat = FlatSerializer::new(&config, MapSink { map, error: None });
+        let result = self.0.serialize(&mut flat);
+        let sink = flat.into_sink();
+        match result {
+            Ok(()) => sink.map.end(),
+            Err(error) => Err(sink.error.unwrap_or_else(|| S::Error::custom(error))),
+        }
     }
 }
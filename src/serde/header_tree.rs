@@ -0,0 +1,120 @@
+//! Headers of a csv file, parsed once into a tree of paths.
+//!
+//! Every node knows the column holding its own value (if any) and all the columns
+//! below it, so the deserializer never has to rescan the header names.
+
+use {
+    crate::flatten_json_value::{FlattenConfig, Segment},
+    indexmap::IndexMap,
+    tap::Tap,
+};
+
+pub type NodeId = usize;
+
+#[derive(Debug, Default)]
+pub struct Node {
+    /// rendered (escaped) path of this node, e.g. `user__address`
+    pub path: String,
+    /// column holding the value of this exact path
+    pub column: Option<usize>,
+    /// direct children, in the order of their first appearance in the headers
+    pub children: IndexMap<Segment<'static>, NodeId>,
+    /// every column at or below this node
+    pub columns: Vec<usize>,
+}
+
+#[derive(Debug)]
+pub struct HeaderTree {
+    nodes: Vec<Node>,
+}
+
+impl HeaderTree {
+    pub const ROOT: NodeId = 0;
+
+    pub fn new<'h>(headers: impl IntoIterator<Item = &'h str>, config: &FlattenConfig) -> Self {
+        Self::with_columns(headers.into_iter().enumerate(), config)
+    }
+
+    /// Tree of only some of the columns, each header given together with its column index
+    pub fn with_columns<'h>(
+        headers: impl IntoIterator<Item = (usize, &'h str)>,
+        config: &FlattenConfig,
+    ) -> Self {
+        let mut tree = Self {
+            nodes: vec![Node::default()],
+        };
+        headers.into_iter().for_each(|(column, header)| {
+            let path = config
+                .split(header)
+                .into_iter()
+                .fold(vec![Self::ROOT], |path, raw| {
+                    let parent = *path.last().expect("starts with the root");
+                    path.tap_mut(|path| path.push(tree.child_or_insert(parent, raw, config)))
+                });
+            let node = *path.last().expect("starts with the root");
+            tree.nodes[node].column = Some(column);
+            path.into_iter()
+                .for_each(|node| tree.nodes[node].columns.push(column));
+        });
+        tree
+    }
+
+    pub fn node(&self, id: NodeId) -> &Node {
+        &self.nodes[id]
+    }
+
+    fn child_or_insert(&mut self, parent: NodeId, raw: &str, config: &FlattenConfig) -> NodeId {
+        let segment = Segment::from_str(raw, config).to_owned();
+        match self.nodes[parent].children.get(&segment) {
+            Some(child) => *child,
+            None => {
+                let child = self.nodes.len();
+                self.nodes.push(Node {
+                    path: config.join(&self.nodes[parent].path, raw),
+                    ..Default::default()
+                });
+                self.nodes[parent].children.insert(segment, child);
+                child
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tree_from_headers() {
+        let tree = HeaderTree::new(
+            [
+                "name",
+                "tags__idx-1",
+                "tags__idx-0",
+                "user__address__city",
+                "user__name",
+            ],
+            &FlattenConfig::DEFAULT,
+        );
+        let root = tree.node(HeaderTree::ROOT);
+        assert_eq!(root.columns, vec![0, 1, 2, 3, 4]);
+        assert_eq!(root.children.len(), 3);
+
+        let tags = tree.node(root.children[&Segment::Field("tags".into())]);
+        assert_eq!(tags.path, "tags");
+        assert_eq!(tags.column, None);
+        assert_eq!(tags.columns, vec![1, 2]);
+        assert_eq!(
+            tags.children.keys().cloned().collect::<Vec<_>>(),
+            vec![Segment::Idx(1), Segment::Idx(0)]
+        );
+
+        let user = tree.node(root.children[&Segment::Field("user".into())]);
+        let city = tree.node(
+            tree.node(user.children[&Segment::Field("address".into())])
+                .children[&Segment::Field("city".into())],
+        );
+        assert_eq!(city.path, "user__address__city");
+        assert_eq!(city.column, Some(3));
+    }
+}
@@ -1,5 +1,16 @@
 use {
-    crate::nested_csv::{read::CsvReaderEnableNestedExt, write::CsvWriterEnableNestedExt},
+    crate::{
+        flatten_json_value::{
+            BytesEncoding, ConfigError, EmptyCells, FlattenConfig, GuessTypes, MultiValue,
+            NonFinite,
+        },
+        nested_csv::{
+            headers::{HeaderMapping, HeaderMatching},
+            leftovers::Leftovers,
+            read::{CsvReaderEnableNestedExt, MissingColumns, RepeatedHeaders, UnknownColumns},
+            write::{self, CsvWriterEnableNestedExt, NestedCsvWriter, UnknownKeys},
+        },
+    },
     anyhow::{Context, Result},
     serde::{Deserialize, Serialize, de::DeserializeOwned},
     std::io::{Read, Seek},
@@ -7,13 +18,13 @@ use {
     tracing::info,
 };
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 struct Child {
     field_1: bool,
     field_2: i32,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 struct Parent {
     child_1: Child,
     child_2: Child,
@@ -157,6 +168,18 @@ fn test_flattening_fixes_the_problem() {
     back_and_forth_nesting_enabled(DATA.iter()).expect("going back and forth with nesting enabled")
 }
 
+#[test]
+fn test_flattened_serializes_as_a_flat_map() -> Result<()> {
+    let flat = serde_json::to_string(&crate::Flattened(PARENT))?;
+    anyhow::ensure!(
+        flat == r#"{"child_1__field_1":true,"child_1__field_2":0,"child_2__field_1":false,"child_2__field_2":1}"#,
+        "got {flat}"
+    );
+    let read = serde_json::from_str::<crate::Flattened<Parent>>(&flat)?;
+    anyhow::ensure!(read.0 == PARENT, "got {read:?}");
+    Ok(())
+}
+
 /// Regression test for the issue where String fields containing numeric values
 /// would fail to deserialize because the intermediate JSON representation
 /// would parse "123" as a number instead of a string.
@@ -195,3 +218,1718 @@ fn test_string_field_with_numeric_value() {
     back_and_forth_nesting_enabled(data.iter())
         .expect("String fields with numeric values should round-trip correctly");
 }
+
+fn write_nested<T>(config: &FlattenConfig, data: &[T]) -> Result<String>
+where
+    T: Serialize + std::fmt::Debug,
+{
+    write_nested_with(data, |w| Ok(w.enable_nested_with_config(config.clone())))
+}
+
+/// same as [`write_nested`], through the nested writer set up by `build`
+fn write_nested_with<T>(
+    data: &[T],
+    build: impl FnOnce(csv::Writer<Vec<u8>>) -> Result<NestedCsvWriter<Vec<u8>, T>>,
+) -> Result<String>
+where
+    T: Serialize + std::fmt::Debug,
+{
+    csv::WriterBuilder::new()
+        .from_writer(Vec::new())
+        .pipe(build)
+        .and_then(|mut w| {
+            data.iter()
+                .try_for_each(|item| w.serialize(item))
+                .context("serializing")
+                .and_then(|()| w.into_inner().context("dropping writer"))
+        })
+        .and_then(|buffer| String::from_utf8(buffer).context("written csv is not utf8"))
+}
+
+fn read_nested<T>(config: &FlattenConfig, written: &str) -> Result<Vec<T>>
+where
+    T: DeserializeOwned + std::fmt::Debug,
+{
+    csv::ReaderBuilder::new()
+        .from_reader(written.as_bytes())
+        .enable_nested_with_config::<T>(config.clone())
+        .context("enabling nesting")?
+        .deserialize()
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("deserializing:\n{written}"))
+}
+
+/// writes and reads back the data, returns the written csv
+fn assert_round_trip<T>(config: &FlattenConfig, data: &[T]) -> Result<String>
+where
+    T: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug,
+{
+    write_nested(config, data).and_then(|written| {
+        read_nested::<T>(config, &written).and_then(|read| {
+            anyhow::ensure!(
+                read == data,
+                "expected:\n{data:#?}\ngot:\n{read:#?}\ncsv:\n{written}"
+            );
+            Ok(written)
+        })
+    })
+}
+
+#[test_log::test]
+fn test_custom_path_syntax_round_trip() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct WithTags {
+        child: Child,
+        tags: Vec<String>,
+    }
+
+    let config = FlattenConfig {
+        separator: ".".into(),
+        array_prefix: "[".into(),
+        array_suffix: "]".into(),
+        ..Default::default()
+    };
+    let data = [WithTags {
+        child: PARENT.child_1,
+        tags: vec!["a".to_string(), "b".to_string()],
+    }];
+
+    assert_round_trip(&config, &data).and_then(|written| {
+        anyhow::ensure!(
+            written.lines().next() == Some("child.field_1,child.field_2,tags.[0],tags.[1]"),
+            "unexpected headers:\n{written}"
+        );
+        Ok(())
+    })
+}
+
+#[test_log::test]
+fn test_ambiguous_field_names_round_trip() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Nested {
+        b: i32,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Ambiguous {
+        #[serde(rename = "a__b")]
+        a_b: i32,
+        a: Nested,
+        map: std::collections::BTreeMap<String, String>,
+    }
+
+    let data = [Ambiguous {
+        a_b: 1,
+        a: Nested { b: 2 },
+        map: [("idx-3", "three"), ("x__y", "xy")]
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .into(),
+    }];
+
+    assert_round_trip(&FlattenConfig::default(), &data).map(drop)
+}
+
+#[test_log::test]
+fn test_escape_in_separator_is_rejected() -> Result<()> {
+    let config = FlattenConfig {
+        separator: "/".into(),
+        escape: '/',
+        ..Default::default()
+    };
+    anyhow::ensure!(
+        write_nested(&config, &[PARENT]).is_err(),
+        "writing should be rejected"
+    );
+    anyhow::ensure!(
+        read_nested::<Parent>(&config, "child_1/field_1\ntrue\n").is_err(),
+        "reading should be rejected"
+    );
+    Ok(())
+}
+
+#[test_log::test]
+fn test_headers_from_type() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct WithOptional {
+        name: String,
+        child: Option<Child>,
+    }
+
+    let write = |data: &[WithOptional]| {
+        write_nested_with(data, |w| {
+            w.enable_nested_with_type_headers(FlattenConfig::default())
+                .context("tracing headers")
+        })
+    };
+
+    anyhow::ensure!(write(&[])? == "name,child__field_1,child__field_2\n");
+
+    // the first record no longer decides the columns
+    let data = [
+        WithOptional {
+            name: "none".to_string(),
+            child: None,
+        },
+        WithOptional {
+            name: "some".to_string(),
+            child: Some(PARENT.child_2),
+        },
+    ];
+    let written = write(&data)?;
+    anyhow::ensure!(
+        written == "name,child__field_1,child__field_2\nnone,,\nsome,false,1\n",
+        "unexpected csv:\n{written}"
+    );
+    read_nested::<WithOptional>(&FlattenConfig::default(), &written).and_then(|read| {
+        anyhow::ensure!(read == data, "got:\n{read:#?}");
+        Ok(())
+    })
+}
+
+#[test_log::test]
+fn test_headers_from_validating_types() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Host {
+        id: std::num::NonZeroU32,
+        ip: std::net::IpAddr,
+        child: Child,
+    }
+
+    let data = [Host {
+        id: std::num::NonZeroU32::MIN,
+        ip: std::net::Ipv4Addr::LOCALHOST.into(),
+        child: PARENT.child_1,
+    }];
+    let written = write_nested_with(&data, |w| {
+        w.enable_nested_with_type_headers(FlattenConfig::default())
+            .context("tracing headers")
+    })?;
+    anyhow::ensure!(
+        written == "id,ip,child__field_1,child__field_2\n1,127.0.0.1,true,0\n",
+        "unexpected csv:\n{written}"
+    );
+    csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(&written.as_bytes()[written.find('\n').map_or(0, |end| end + 1)..])
+        .enable_nested_with_type_headers::<Host>(FlattenConfig::default())
+        .context("tracing headers")?
+        .deserialize()
+        .collect::<Result<Vec<_>, _>>()
+        .context("type headers")
+        .and_then(|read| {
+            anyhow::ensure!(read == data, "got:\n{read:#?}");
+            Ok(())
+        })
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Heterogeneous {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    child: Option<Child>,
+    tags: Vec<String>,
+}
+
+fn write_with_unknown_keys(unknown_keys: UnknownKeys) -> Result<String> {
+    let data = [
+        Heterogeneous {
+            name: "a".to_string(),
+            child: None,
+            tags: vec!["x".to_string()],
+        },
+        Heterogeneous {
+            name: "b".to_string(),
+            child: Some(PARENT.child_1),
+            tags: vec!["y".to_string(), "z".to_string()],
+        },
+    ];
+    write_nested_with(&data, |w| {
+        Ok(w.enable_nested().with_unknown_keys(unknown_keys))
+    })
+}
+
+#[test_log::test]
+fn test_unknown_keys_policy() -> Result<()> {
+    anyhow::ensure!(
+        write_with_unknown_keys(UnknownKeys::Error).is_err(),
+        "extra keys should be an error by default"
+    );
+    write_with_unknown_keys(UnknownKeys::Drop).and_then(|written| {
+        anyhow::ensure!(
+            written == "name,tags__idx-0\na,x\nb,y\n",
+            "unexpected csv:\n{written}"
+        );
+        Ok(())
+    })?;
+    write_with_unknown_keys(UnknownKeys::Extend).and_then(|written| {
+        anyhow::ensure!(
+            written
+                == "name,tags__idx-0,child__field_1,child__field_2,tags__idx-1\n\
+                    a,x,,,\n\
+                    b,y,true,0,z\n",
+            "unexpected csv:\n{written}"
+        );
+        Ok(())
+    })?;
+
+    // a dropped writer still writes the buffered rows
+    let data = [Heterogeneous {
+        name: "a".to_string(),
+        child: None,
+        tags: vec![],
+    }];
+    let mut buffer = Vec::new();
+    csv::Writer::from_writer(&mut buffer)
+        .enable_nested()
+        .with_unknown_keys(UnknownKeys::Extend)
+        .pipe_ref_mut(|w| data.iter().try_for_each(|item| w.serialize(item)))?;
+    anyhow::ensure!(buffer == b"name\na\n", "got:\n{buffer:?}");
+
+    let mut writer = csv::Writer::from_writer(Vec::new())
+        .enable_nested()
+        .with_unknown_keys(UnknownKeys::Extend)
+        .with_max_buffered_rows(1);
+    writer.serialize(&data[0])?;
+    anyhow::ensure!(
+        matches!(
+            writer.serialize(&data[0]),
+            Err(write::Error::TooManyBufferedRows { limit: 1 })
+        ),
+        "the second buffered row should be rejected"
+    );
+    Ok(())
+}
+
+#[test_log::test]
+fn test_fixed_width_arrays() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Order {
+        id: u32,
+        items: Vec<Child>,
+        tags: Vec<String>,
+    }
+
+    let config = FlattenConfig::default()
+        .with_array_width("items", 2)
+        .with_array_width("tags", 3);
+    let data = [
+        Order {
+            id: 1,
+            items: vec![],
+            tags: vec!["a".to_string()],
+        },
+        Order {
+            id: 2,
+            items: vec![PARENT.child_1, PARENT.child_2],
+            tags: vec!["b".to_string(), "c".to_string(), "d".to_string()],
+        },
+    ];
+
+    // without a width the columns of `items` depend on the data
+    let without_width = csv::Writer::from_writer(Vec::new())
+        .enable_nested_with_type_headers::<Order>(
+            FlattenConfig::default().with_array_width("tags", 3),
+        );
+    anyhow::ensure!(
+        matches!(
+            &without_width,
+            Err(write::Error::DataDependentColumns { paths }) if paths == &["items"]
+        ),
+        "got: {:?}",
+        without_width.err()
+    );
+
+    let written = write_nested_with(&data, |w| {
+        w.enable_nested_with_type_headers(config.clone())
+            .context("tracing headers")
+    })?;
+    anyhow::ensure!(
+        written
+            == "id,items__idx-0__field_1,items__idx-0__field_2,items__idx-1__field_1,items__idx-1__field_2,tags__idx-0,tags__idx-1,tags__idx-2\n\
+                1,,,,,a,,\n\
+                2,true,0,false,1,b,c,d\n",
+        "unexpected csv:\n{written}"
+    );
+    read_nested::<Order>(&config, &written).and_then(|read| {
+        anyhow::ensure!(read == data, "got:\n{read:#?}");
+        Ok(())
+    })?;
+
+    // scalar arrays are padded without the type's help too
+    assert_round_trip(&config, &data[..1])?;
+
+    anyhow::ensure!(
+        write_nested(&config.with_array_width("tags", 1), &data[1..]).is_err(),
+        "arrays longer than their width should be an error"
+    );
+    Ok(())
+}
+
+#[test_log::test]
+fn test_trailing_empty_elements_round_trip() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Trailing {
+        ids: Vec<Option<u32>>,
+        tags: Vec<String>,
+    }
+
+    let data = [Trailing {
+        ids: vec![Some(1), None],
+        tags: vec!["a".to_string(), String::new()],
+    }];
+
+    let written = assert_round_trip(&FlattenConfig::default(), &data)?;
+    anyhow::ensure!(
+        written == "ids__idx-0,ids__idx-1,tags__idx-0,tags__idx-1\n1,,a,\n",
+        "unexpected csv:\n{written}"
+    );
+
+    // asked for, they are dropped like padding
+    read_nested::<Trailing>(&FlattenConfig::default().with_trimmed_arrays(), &written).and_then(
+        |read| {
+            anyhow::ensure!(
+                read == [Trailing {
+                    ids: vec![Some(1)],
+                    tags: vec!["a".to_string()],
+                }],
+                "got:\n{read:#?}"
+            );
+            Ok(())
+        },
+    )
+}
+
+#[test_log::test]
+fn test_multi_value_cells_round_trip() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Tagged {
+        tags: Vec<String>,
+        ids: Vec<u32>,
+        children: Vec<Child>,
+    }
+
+    let config = FlattenConfig::default().with_multi_value(MultiValue::new(';'));
+    let data = [
+        Tagged {
+            tags: vec!["a".to_string(), "b;c".to_string()],
+            ids: vec![1, 2, 3],
+            children: vec![PARENT.child_1],
+        },
+        Tagged {
+            tags: vec!["d".to_string(), "e".to_string()],
+            ids: vec![4, 5, 6],
+            children: vec![PARENT.child_2],
+        },
+    ];
+
+    assert_round_trip(&config, &data).and_then(|written| {
+        anyhow::ensure!(
+            written.starts_with(
+                "tags,ids,children__idx-0__field_1,children__idx-0__field_2\n\
+                 a;b\\;c,1;2;3,true,0\n"
+            ),
+            "unexpected csv:\n{written}"
+        );
+        Ok(())
+    })
+}
+
+#[test_log::test]
+fn test_json_in_cell_round_trip() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Deep {
+        parent: Parent,
+        values: Vec<Option<u8>>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct WithPayload {
+        id: u32,
+        deep: Deep,
+        metadata: serde_json::Value,
+        pairs: std::collections::BTreeMap<String, (u8, bool)>,
+    }
+
+    let config = FlattenConfig::default()
+        .with_max_depth(2)
+        .with_opaque_path("metadata");
+    let data = [WithPayload {
+        id: 7,
+        deep: Deep {
+            parent: PARENT,
+            values: vec![Some(1), None],
+        },
+        metadata: serde_json::json!({ "free": ["form", { "x": null }] }),
+        pairs: [("a".to_string(), (1, true))].into(),
+    }];
+
+    assert_round_trip(&config, &data).and_then(|written| {
+        anyhow::ensure!(
+            written.lines().next() == Some("id,deep__parent,deep__values,metadata,pairs__a"),
+            "unexpected csv:\n{written}"
+        );
+        Ok(())
+    })
+}
+
+#[test_log::test]
+fn test_bracket_strings_round_trip() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Post {
+        title: String,
+        tags: Vec<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Entry {
+        value: serde_json::Value,
+        #[serde(flatten)]
+        post: Post,
+    }
+
+    let data = [Entry {
+        value: serde_json::json!("[x]"),
+        post: Post {
+            title: "[draft] x".into(),
+            tags: vec!["{a".into(), "[]".into()],
+        },
+    }];
+    // only cells of opaque paths are JSON
+    assert_round_trip(&FlattenConfig::default(), &data)?;
+    assert_round_trip(&FlattenConfig::default().with_opaque_path("other"), &data).map(drop)
+}
+
+#[test_log::test]
+fn test_empty_containers_round_trip() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct WithEmpty {
+        tags: Vec<String>,
+        maybe: Option<Vec<u32>>,
+        counts: std::collections::BTreeMap<String, u32>,
+    }
+
+    // the union of columns pads `tags` of the first row
+    let config = FlattenConfig::default()
+        .with_empty_markers()
+        .with_trimmed_arrays();
+    let data = [
+        WithEmpty {
+            tags: vec![],
+            maybe: None,
+            counts: Default::default(),
+        },
+        WithEmpty {
+            tags: vec!["a".to_string()],
+            maybe: Some(vec![]),
+            counts: [("x".to_string(), 1)].into(),
+        },
+    ];
+
+    let written = write_nested_with(&data, |w| {
+        Ok(w.enable_nested_with_config(config.clone())
+            .with_unknown_keys(UnknownKeys::Extend))
+    })?;
+    anyhow::ensure!(
+        written == "tags,maybe,counts,tags__idx-0,counts__x\n[],,{},,\n,[],,a,1\n",
+        "unexpected csv:\n{written}"
+    );
+    read_nested::<WithEmpty>(&config, &written).and_then(|read| {
+        anyhow::ensure!(read == data, "got:\n{read:#?}");
+        Ok(())
+    })
+}
+
+#[test_log::test]
+fn test_guessed_cell_types_round_trip() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(untagged)]
+    enum Id {
+        Num(u64),
+        Text(String),
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Guessed {
+        id: Id,
+        zip: Id,
+        value: serde_json::Value,
+    }
+
+    let data = [
+        Guessed {
+            id: Id::Num(42),
+            zip: Id::Text("01234".to_string()),
+            value: serde_json::json!(-1.5),
+        },
+        Guessed {
+            id: Id::Text("abc".to_string()),
+            zip: Id::Num(10001),
+            value: serde_json::json!(true),
+        },
+        Guessed {
+            id: Id::Num(7),
+            zip: Id::Text("NaN".to_string()),
+            value: serde_json::Value::Null,
+        },
+    ];
+    assert_round_trip(&FlattenConfig::default(), &data)?;
+
+    // with guessing disabled every cell is a string
+    let config = FlattenConfig::default().with_guess_types(GuessTypes::NONE);
+    write_nested(&config, &data[..1])
+        .and_then(|written| read_nested::<Guessed>(&config, &written))
+        .and_then(|read| {
+            anyhow::ensure!(
+                read == [Guessed {
+                    id: Id::Text("42".to_string()),
+                    zip: Id::Text("01234".to_string()),
+                    value: serde_json::json!("-1.5"),
+                }],
+                "got:\n{read:#?}"
+            );
+            Ok(())
+        })
+}
+
+#[test_log::test]
+fn test_text_retries() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Inner {
+        count: u32,
+        code: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Outer {
+        #[serde(flatten)]
+        inner: Inner,
+    }
+
+    // both cells hold the same text, only `code` has to be read as text
+    let file = "count,code\n42,42\n";
+    let expected = [Outer {
+        inner: Inner {
+            count: 42,
+            code: "42".into(),
+        },
+    }];
+    anyhow::ensure!(
+        read_nested::<Outer>(&FlattenConfig::default(), file).is_err(),
+        "records should only be read once by default"
+    );
+    read_nested::<Outer>(&FlattenConfig::default().with_text_retries(4), file).and_then(
+        |read| {
+            anyhow::ensure!(read == expected, "got:\n{read:#?}");
+            Ok(())
+        },
+    )?;
+
+    // custom messages don't name the cell, the guessed ones are tried in turn
+    #[derive(Deserialize, Debug, Clone, PartialEq)]
+    struct Code(String);
+
+    #[derive(Deserialize, Debug, Clone, PartialEq)]
+    struct Custom {
+        #[serde(flatten)]
+        inner: CustomInner,
+    }
+
+    #[derive(Deserialize, Debug, Clone, PartialEq)]
+    struct CustomInner {
+        count: u32,
+        #[serde(deserialize_with = "code")]
+        code: Code,
+    }
+
+    fn code<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Code, D::Error> {
+        String::deserialize(deserializer)
+            .map(Code)
+            .map_err(|_| serde::de::Error::custom("not a code"))
+    }
+
+    read_nested::<Custom>(&FlattenConfig::default().with_text_retries(4), file).and_then(|read| {
+        anyhow::ensure!(
+            read == [Custom {
+                inner: CustomInner {
+                    count: 42,
+                    code: Code("42".into()),
+                },
+            }],
+            "got:\n{read:#?}"
+        );
+        Ok(())
+    })
+}
+
+#[test_log::test]
+fn test_enum_representations_round_trip() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    enum External {
+        Empty,
+        Circle { radius: f64 },
+        Rect(u32, u32),
+        Named(String),
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(tag = "type")]
+    enum Internal {
+        Empty,
+        Circle { radius: f64 },
+        Labelled(Child),
+        Note { text: String },
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(tag = "t", content = "c")]
+    enum Adjacent {
+        Empty,
+        Circle { radius: f64 },
+        Rect(u32, u32),
+        Named(String),
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(untagged)]
+    enum Untagged {
+        Circle { radius: f64 },
+        Rect(u32, u32),
+        Named(String),
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Shapes {
+        external: External,
+        internal: Internal,
+        adjacent: Adjacent,
+        untagged: Untagged,
+    }
+
+    let data = [
+        Shapes {
+            external: External::Circle { radius: 1.5 },
+            internal: Internal::Circle { radius: 2.5 },
+            adjacent: Adjacent::Circle { radius: 3.5 },
+            untagged: Untagged::Circle { radius: 4.5 },
+        },
+        Shapes {
+            external: External::Rect(1, 2),
+            internal: Internal::Labelled(PARENT.child_1),
+            adjacent: Adjacent::Rect(3, 4),
+            untagged: Untagged::Rect(5, 6),
+        },
+        Shapes {
+            external: External::Named("a".to_string()),
+            internal: Internal::Empty,
+            adjacent: Adjacent::Named("b".to_string()),
+            untagged: Untagged::Named("c".to_string()),
+        },
+        Shapes {
+            external: External::Empty,
+            internal: Internal::Empty,
+            adjacent: Adjacent::Empty,
+            untagged: Untagged::Named("d".to_string()),
+        },
+        // text which looks like a number or JSON is still text
+        Shapes {
+            external: External::Named("42".to_string()),
+            internal: Internal::Note {
+                text: "42".to_string(),
+            },
+            adjacent: Adjacent::Named("7".to_string()),
+            untagged: Untagged::Named("7".to_string()),
+        },
+        Shapes {
+            external: External::Named("[y]".to_string()),
+            internal: Internal::Note {
+                text: "{x".to_string(),
+            },
+            adjacent: Adjacent::Named("{x}".to_string()),
+            untagged: Untagged::Named("[y]".to_string()),
+        },
+    ];
+    // numeric strings of internally tagged and untagged enums are first read as numbers
+    let config = FlattenConfig::default().with_text_retries(8);
+    data.iter().try_for_each(|shapes| {
+        assert_round_trip(&config, std::slice::from_ref(shapes)).map(|_| ())
+    })?;
+
+    // all the variants in one file, every record only fills the columns of its own variant
+    write_nested_with(&data, |w| {
+        Ok(w.enable_nested_with_config(config.clone())
+            .with_unknown_keys(UnknownKeys::Extend))
+    })
+    .and_then(|written| read_nested::<Shapes>(&config, &written))
+    .and_then(|read| {
+        anyhow::ensure!(read == data, "got:\n{read:#?}");
+        Ok(())
+    })
+}
+
+#[test_log::test]
+fn test_serde_flatten_round_trip() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Inner {
+        count: u32,
+        active: bool,
+        zip: String,
+        child: Child,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Outer {
+        name: String,
+        #[serde(flatten)]
+        inner: Inner,
+        #[serde(flatten)]
+        extra: Leftovers,
+    }
+
+    // `zip` is first read as a number
+    let config = FlattenConfig::default().with_text_retries(8);
+    let data = [Outer {
+        name: "a".into(),
+        inner: Inner {
+            count: 3,
+            active: true,
+            zip: "10001".into(),
+            child: PARENT.child_1,
+        },
+        extra: serde_json::json!({ "note": "x", "nested": { "deep": 4, "list": [true] } })
+            .as_object()
+            .cloned()
+            .map(Leftovers::from)
+            .context("leftovers")?,
+    }];
+    let written = assert_round_trip(&config, &data)?;
+    anyhow::ensure!(
+        written.starts_with(
+            "name,count,active,zip,child__field_1,child__field_2,note,nested__deep,nested__list__idx-0\n"
+        ),
+        "unexpected csv:\n{written}"
+    );
+    read_nested::<Outer>(&config, &written).and_then(|read| {
+        let columns = read[0].extra.columns(&config);
+        anyhow::ensure!(
+            columns
+                == [
+                    ("note", "x"),
+                    ("nested__deep", "4"),
+                    ("nested__list__idx-0", "true")
+                ]
+                .map(|(header, cell)| (header.to_string(), cell.to_string())),
+            "got: {columns:?}"
+        );
+        Ok(())
+    })?;
+
+    // leftover cells keep their text, unless changed
+    let file = "name,count,active,zip,child__field_1,child__field_2,price,big\n\
+                a,3,true,10001,true,0,1.50,1e3\n";
+    let mut read = read_nested::<Outer>(&config, file)?;
+    anyhow::ensure!(
+        read[0].extra.cells["price"] == 1.5 && read[0].extra.cells["big"] == 1000.0,
+        "got: {:?}",
+        read[0].extra.cells
+    );
+    let columns = read[0].extra.columns(&config);
+    anyhow::ensure!(
+        columns
+            == [("price", "1.50"), ("big", "1e3")]
+                .map(|(header, cell)| (header.to_string(), cell.to_string())),
+        "got: {columns:?}"
+    );
+    let written = write_nested(&config, &read)?;
+    anyhow::ensure!(written == file, "unexpected csv:\n{written}");
+    read[0].extra.cells.insert("price".into(), 2.into());
+    let columns = read[0].extra.columns(&config);
+    anyhow::ensure!(
+        columns
+            == [("price", "2"), ("big", "1e3")]
+                .map(|(header, cell)| (header.to_string(), cell.to_string())),
+        "got: {columns:?}"
+    );
+
+    // below the top level the leftover columns keep their full header names
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Wrapper {
+        id: u32,
+        outer: Outer,
+    }
+
+    let wrapped = data.map(|outer| Wrapper { id: 1, outer });
+    assert_round_trip(&config, &wrapped)
+        .and_then(|written| read_nested::<Wrapper>(&config, &written))
+        .and_then(|read| {
+            let columns = read[0].outer.extra.columns(&config);
+            anyhow::ensure!(
+                columns
+                    == [
+                        ("outer__note", "x"),
+                        ("outer__nested__deep", "4"),
+                        ("outer__nested__list__idx-0", "true")
+                    ]
+                    .map(|(header, cell)| (header.to_string(), cell.to_string())),
+                "got: {columns:?}"
+            );
+            Ok(())
+        })
+}
+
+#[test_log::test]
+fn test_128_bit_integers_round_trip() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Big {
+        id: u128,
+        delta: i128,
+        nested: Option<(u128, i128)>,
+    }
+
+    let data = [
+        Big {
+            id: u128::MAX,
+            delta: i128::MIN,
+            nested: Some((u128::from(u64::MAX) + 1, -1)),
+        },
+        Big {
+            id: 0,
+            delta: 1,
+            nested: None,
+        },
+    ];
+    assert_round_trip(&FlattenConfig::default(), &data).and_then(|written| {
+        anyhow::ensure!(
+            written.contains(&format!("{},{}", u128::MAX, i128::MIN)),
+            "unexpected csv:\n{written}"
+        );
+        Ok(())
+    })?;
+    assert_round_trip(&FlattenConfig::default().with_max_depth(1), &data).map(|_| ())
+}
+
+#[cfg(feature = "arbitrary_precision")]
+#[test_log::test]
+fn test_arbitrary_precision_numbers_round_trip() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Amounts {
+        values: serde_json::Value,
+    }
+
+    let data = [Amounts {
+        values: serde_json::from_str(
+            r#"{ "exact": 0.1000000000000000055511151231257827, "big": 340282366920938463463374607431768211455, "small": 1.5 }"#,
+        )?,
+    }];
+    let written = assert_round_trip(&FlattenConfig::default(), &data)?;
+    anyhow::ensure!(
+        written.ends_with(
+            "\n0.1000000000000000055511151231257827,340282366920938463463374607431768211455,1.5\n"
+        ),
+        "unexpected csv:\n{written}"
+    );
+
+    // flatten_json_value keeps the exact text too
+    let flat = crate::flatten_json_value::flatten::flattened(data[0].values.clone())?;
+    anyhow::ensure!(
+        flat["big"]
+            == serde_json::from_str::<serde_json::Value>(
+                "340282366920938463463374607431768211455"
+            )?,
+        "got: {flat:?}"
+    );
+    Ok(())
+}
+
+#[test_log::test]
+fn test_non_finite_floats_round_trip() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Reading {
+        value: f64,
+        low: f32,
+        maybe: Option<f64>,
+    }
+
+    let data = [
+        Reading {
+            value: f64::NAN,
+            low: f32::NEG_INFINITY,
+            maybe: Some(f64::INFINITY),
+        },
+        Reading {
+            value: 1.5,
+            low: f32::NAN,
+            maybe: None,
+        },
+    ];
+    // NaN != NaN, so the bits are compared instead
+    let bits = |readings: &[Reading]| {
+        readings
+            .iter()
+            .map(|r| {
+                (
+                    r.value.to_bits(),
+                    r.low.to_bits(),
+                    r.maybe.map(f64::to_bits),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let check = |config: &FlattenConfig, expected: &str| {
+        write_nested(config, &data).and_then(|written| {
+            anyhow::ensure!(written == expected, "unexpected csv:\n{written}");
+            read_nested::<Reading>(config, &written).and_then(|read| {
+                anyhow::ensure!(bits(&read) == bits(&data), "got:\n{read:#?}");
+                Ok(())
+            })
+        })
+    };
+
+    check(
+        &FlattenConfig::default(),
+        "value,low,maybe\nNaN,-inf,inf\n1.5,NaN,\n",
+    )?;
+    check(
+        &FlattenConfig::default().with_non_finite(NonFinite {
+            nan: "n/a".into(),
+            infinity: "+Inf".into(),
+            neg_infinity: "-Inf".into(),
+        }),
+        "value,low,maybe\nn/a,-Inf,+Inf\n1.5,n/a,\n",
+    )
+}
+
+#[test_log::test]
+fn test_non_string_map_keys_round_trip() -> Result<()> {
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    enum Region {
+        North,
+        South,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    struct Cell(u8, String);
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Keyed {
+        children: BTreeMap<u32, Child>,
+        regions: BTreeMap<Region, i64>,
+        grid: BTreeMap<(i8, bool), String>,
+        cells: BTreeMap<Cell, u8>,
+    }
+
+    let data = [Keyed {
+        children: [(7, PARENT.child_1), (42, PARENT.child_2)].into(),
+        regions: [(Region::North, -1), (Region::South, 2)].into(),
+        grid: [((-1, true), "a".to_string())].into(),
+        cells: [(Cell(1, "x|y".to_string()), 3)].into(),
+    }];
+    assert_round_trip(&FlattenConfig::default(), &data).and_then(|written| {
+        anyhow::ensure!(
+            written.starts_with(
+                "children__7__field_1,children__7__field_2,children__42__field_1,\
+                 children__42__field_2,regions__North,regions__South,grid__-1|true,cells__1|x\\\\|y\n"
+            ),
+            "unexpected csv:\n{written}"
+        );
+        Ok(())
+    })
+}
+
+#[test_log::test]
+fn test_null_token_and_empty_cells() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Nullable {
+        name: Option<String>,
+        count: Option<u32>,
+        child: Option<Child>,
+    }
+
+    let data = [
+        Nullable {
+            name: Some(String::new()),
+            count: None,
+            child: Some(PARENT.child_1),
+        },
+        Nullable {
+            name: None,
+            count: Some(1),
+            child: None,
+        },
+    ];
+    let config = FlattenConfig::default()
+        .with_null_token("\\N")
+        .with_empty_cells(EmptyCells::EmptyString);
+    assert_round_trip(&config, &data).and_then(|written| {
+        anyhow::ensure!(
+            written == "name,count,child__field_1,child__field_2\n,\\N,true,0\n\\N,1,\\N,\\N\n",
+            "unexpected csv:\n{written}"
+        );
+        Ok(())
+    })?;
+
+    // by default `Some("")` can't be told apart from `None`
+    read_nested::<Nullable>(
+        &FlattenConfig::default(),
+        &write_nested(&FlattenConfig::default(), &data)?,
+    )
+    .and_then(|read| {
+        anyhow::ensure!(read[0].name.is_none(), "got:\n{read:#?}");
+        Ok(())
+    })?;
+
+    let file = "name,count,child__field_1,child__field_2\nNULL,,NULL,NULL\n";
+    read_nested::<Nullable>(&FlattenConfig::default().with_null_token("NULL"), file).and_then(
+        |read| {
+            anyhow::ensure!(
+                read == [Nullable {
+                    name: None,
+                    count: None,
+                    child: None,
+                }],
+                "got:\n{read:#?}"
+            );
+            Ok(())
+        },
+    )?;
+    anyhow::ensure!(
+        read_nested::<Nullable>(
+            &FlattenConfig::default()
+                .with_null_token("NULL")
+                .with_empty_cells(EmptyCells::Error),
+            file,
+        )
+        .is_err(),
+        "the empty count cell should be rejected"
+    );
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Named {
+        name: String,
+        count: u32,
+    }
+
+    anyhow::ensure!(
+        read_nested::<Named>(
+            &FlattenConfig::default().with_empty_cells(EmptyCells::Error),
+            "name,count\n,1\n",
+        )
+        .is_err(),
+        "the empty name cell should be rejected"
+    );
+
+    // without a null token `None` is written as an empty cell, which reads back as ""
+    let config = FlattenConfig::default().with_empty_cells(EmptyCells::EmptyString);
+    anyhow::ensure!(
+        config.validate() == Err(ConfigError::EmptyStringWithoutNullToken),
+        "empty strings without a null token should be rejected"
+    );
+    anyhow::ensure!(
+        write_nested(&config, &data).is_err(),
+        "writing with empty strings and no null token should fail"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_presence_marker() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Inner {
+        a: Option<u32>,
+        b: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Outer {
+        id: u32,
+        inner: Option<Inner>,
+    }
+
+    let data = [
+        Outer {
+            id: 1,
+            inner: Some(Inner { a: None, b: None }),
+        },
+        Outer { id: 2, inner: None },
+        Outer {
+            id: 3,
+            inner: Some(Inner {
+                a: Some(4),
+                b: Some("x".into()),
+            }),
+        },
+    ];
+    let config = FlattenConfig::default().with_presence_marker("present");
+    assert_round_trip(&config, &data).and_then(|written| {
+        anyhow::ensure!(
+            written == "id,inner__present,inner__a,inner__b\n1,true,,\n2,,,\n3,true,4,x\n",
+            "unexpected csv:\n{written}"
+        );
+        Ok(())
+    })?;
+
+    // without the marker an all-`None` struct reads back as `None`
+    read_nested::<Outer>(
+        &FlattenConfig::default(),
+        &write_nested(&FlattenConfig::default(), &data)?,
+    )
+    .and_then(|read| {
+        anyhow::ensure!(read[0].inner.is_none(), "got:\n{read:#?}");
+        Ok(())
+    })?;
+
+    // the marker is the only thing deciding, filled cells without it are ignored
+    let file = "id,inner__present,inner__a,inner__b\n1,,4,x\n";
+    read_nested::<Outer>(&config, file).and_then(|read| {
+        anyhow::ensure!(read == [Outer { id: 1, inner: None }], "got:\n{read:#?}");
+        Ok(())
+    })
+}
+
+#[test]
+fn test_presence_marker_named_field() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Flag {
+        present: bool,
+        flags: Vec<bool>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Outer {
+        flag: Flag,
+        optional: Option<Vec<u32>>,
+    }
+
+    // only the content of an `Option` has the marker, elsewhere it is a plain field
+    let data = [Outer {
+        flag: Flag {
+            present: false,
+            flags: vec![true],
+        },
+        optional: Some(vec![1]),
+    }];
+    let config = FlattenConfig::default().with_presence_marker("present");
+    assert_round_trip(&config, &data).and_then(|written| {
+        anyhow::ensure!(
+            written.starts_with("flag__present,flag__flags__idx-0,optional__present,"),
+            "unexpected csv:\n{written}"
+        );
+        Ok(())
+    })?;
+    assert_round_trip(&config, &[data[0].flag.clone()])?;
+
+    // inside an `Option` the field and the marker would share a column
+    let error = write_nested(&config, &[[Some(data[0].flag.clone())]])
+        .expect_err("the marker clashes with the field");
+    anyhow::ensure!(
+        format!("{error:#}").contains("'idx-0__present'"),
+        "unexpected error: {error:#}"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_bytes_round_trip() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Blob {
+        #[serde(with = "serde_bytes")]
+        hash: Vec<u8>,
+        thumbnail: Option<serde_bytes::ByteBuf>,
+    }
+
+    let data = [
+        Blob {
+            hash: vec![0xde, 0xad, 0xbe, 0xef],
+            thumbnail: Some(serde_bytes::ByteBuf::from(b"hi!".to_vec())),
+        },
+        Blob {
+            hash: Vec::new(),
+            thumbnail: None,
+        },
+    ];
+    [
+        (BytesEncoding::Base64, "hash,thumbnail\n3q2+7w==,aGkh\n,\n"),
+        (BytesEncoding::Hex, "hash,thumbnail\ndeadbeef,686921\n,\n"),
+    ]
+    .into_iter()
+    .try_for_each(|(encoding, expected)| {
+        assert_round_trip(&FlattenConfig::default().with_bytes(encoding), &data).and_then(
+            |written| {
+                anyhow::ensure!(written == expected, "unexpected csv:\n{written}");
+                Ok(())
+            },
+        )
+    })?;
+
+    // by default bytes are an array of numbers, like in serde_json
+    assert_round_trip(&FlattenConfig::default(), &data[..1]).and_then(|written| {
+        anyhow::ensure!(
+            written.starts_with("hash__idx-0,hash__idx-1,"),
+            "unexpected csv:\n{written}"
+        );
+        Ok(())
+    })?;
+
+    anyhow::ensure!(
+        read_nested::<Blob>(
+            &FlattenConfig::default().with_bytes(BytesEncoding::Hex),
+            "hash,thumbnail\nabc,\n",
+        )
+        .is_err(),
+        "an odd number of hex digits should be rejected"
+    );
+
+    // without `serde_bytes` a `Vec<u8>` is a sequence of numbers, whatever the encoding
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Plain {
+        hash: Vec<u8>,
+    }
+    let plain = [Plain {
+        hash: data[0].hash.clone(),
+    }];
+    assert_round_trip(
+        &FlattenConfig::default().with_bytes(BytesEncoding::Base64),
+        &plain,
+    )
+    .and_then(|written| {
+        anyhow::ensure!(
+            written == "hash__idx-0,hash__idx-1,hash__idx-2,hash__idx-3\n222,173,190,239\n",
+            "unexpected csv:\n{written}"
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn test_headerless_reading() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Feed {
+        id: u32,
+        child: Child,
+        note: Option<String>,
+    }
+
+    let file = "1,true,-1,hello\n2,false,7,\n";
+    let expected = [
+        Feed {
+            id: 1,
+            child: Child {
+                field_1: true,
+                field_2: -1,
+            },
+            note: Some("hello".into()),
+        },
+        Feed {
+            id: 2,
+            child: Child {
+                field_1: false,
+                field_2: 7,
+            },
+            note: None,
+        },
+    ];
+    let reader = || {
+        csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(file.as_bytes())
+    };
+
+    anyhow::ensure!(
+        matches!(
+            reader().enable_nested::<Feed>(),
+            Err(crate::nested_csv::read::Error::NoHeaders)
+        ),
+        "header-less reader without explicit headers should be rejected"
+    );
+    reader()
+        .enable_nested_with_headers::<Feed>(
+            ["id", "child__field_1", "child__field_2", "note"],
+            FlattenConfig::default(),
+        )
+        .context("explicit headers")?
+        .deserialize()
+        .collect::<Result<Vec<_>, _>>()
+        .context("explicit headers")
+        .and_then(|read| {
+            anyhow::ensure!(read == expected, "got:\n{read:#?}");
+            Ok(())
+        })?;
+    reader()
+        .enable_nested_with_type_headers::<Feed>(FlattenConfig::default())
+        .context("tracing headers")?
+        .deserialize()
+        .collect::<Result<Vec<_>, _>>()
+        .context("type headers")
+        .and_then(|read| {
+            anyhow::ensure!(read == expected, "got:\n{read:#?}");
+            Ok(())
+        })?;
+
+    // cells beyond the headers aren't silently dropped
+    anyhow::ensure!(
+        reader()
+            .enable_nested_with_headers::<Feed>(
+                ["id", "child__field_1", "child__field_2"],
+                FlattenConfig::default(),
+            )
+            .context("explicit headers")?
+            .deserialize()
+            .next()
+            .is_some_and(|read| matches!(
+                read,
+                Err(crate::nested_csv::read::Error::ExtraCells {
+                    cells: 4,
+                    headers: 3,
+                    ..
+                })
+            )),
+        "a record longer than the headers should be an error"
+    );
+
+    // sequences need a width to have columns
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Tagged {
+        id: u32,
+        tags: Vec<String>,
+    }
+
+    let read_tagged = |config| {
+        csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader("1,a,b\n2,c,\n".as_bytes())
+            .enable_nested_with_type_headers::<Tagged>(config)
+    };
+    anyhow::ensure!(
+        matches!(
+            read_tagged(FlattenConfig::default()),
+            Err(crate::nested_csv::read::Error::DataDependentColumns { .. })
+        ),
+        "a sequence without a width should be rejected"
+    );
+    read_tagged(FlattenConfig::default().with_array_width("tags", 2))
+        .context("tracing headers")?
+        .deserialize()
+        .collect::<Result<Vec<_>, _>>()
+        .context("sequence")
+        .and_then(|read| {
+            anyhow::ensure!(
+                read == [
+                    Tagged {
+                        id: 1,
+                        tags: vec!["a".into(), "b".into()],
+                    },
+                    Tagged {
+                        id: 2,
+                        tags: vec!["c".into()],
+                    },
+                ],
+                "got:\n{read:#?}"
+            );
+            Ok(())
+        })
+}
+
+#[test]
+fn test_unknown_columns() -> Result<()> {
+    let file = "child_1__field_1,child_1__field_2,child_1__field_3,child_2__field_1,child_2__field_2,comment\n\
+                true,0,x,false,1,hi\n";
+    let read = |unknown_columns| {
+        csv::ReaderBuilder::new()
+            .from_reader(file.as_bytes())
+            .enable_nested::<Parent>()
+            .context("enabling nesting")?
+            .with_unknown_columns(unknown_columns)
+            .deserialize()
+            .collect::<Result<Vec<_>, _>>()
+            .context("deserializing")
+    };
+
+    [
+        UnknownColumns::Keep,
+        UnknownColumns::Skip,
+        UnknownColumns::Warn,
+    ]
+    .into_iter()
+    .try_for_each(|unknown_columns| {
+        read(unknown_columns).and_then(|read| {
+            anyhow::ensure!(read == [PARENT], "{unknown_columns:?} got:\n{read:#?}");
+            Ok(())
+        })
+    })?;
+
+    let error = read(UnknownColumns::Error)
+        .err()
+        .context("unknown columns should be rejected")?;
+    anyhow::ensure!(
+        matches!(
+            error.downcast_ref(),
+            Some(crate::nested_csv::read::Error::UnknownColumns { columns })
+                if columns == &["child_1__field_3", "comment"]
+        ),
+        "unexpected error: {error:?}"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_header_mapping() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Address {
+        zip: String,
+        city: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Order {
+        id: u32,
+        customer: Address,
+    }
+
+    let data = [Order {
+        id: 7,
+        customer: Address {
+            zip: "01234".into(),
+            city: "Gdańsk".into(),
+        },
+    }];
+    let read = |mapping: &HeaderMapping, file: &str| {
+        csv::ReaderBuilder::new()
+            .from_reader(file.as_bytes())
+            .enable_nested::<Order>()
+            .context("enabling nesting")?
+            .with_header_mapping(mapping.clone())
+            .deserialize()
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("deserializing:\n{file}"))
+    };
+
+    let mapping = HeaderMapping::default()
+        .with_rename("customer", "client")
+        .with_label("id", "Order ID");
+    let written = write_nested_with(&data, |w| {
+        Ok(w.enable_nested().with_header_mapping(mapping.clone()))
+    })?;
+    anyhow::ensure!(
+        written == "Order ID,client__zip,client__city\n7,01234,Gdańsk\n",
+        "unexpected csv:\n{written}"
+    );
+    read(&mapping, &written).and_then(|read| {
+        anyhow::ensure!(read == data, "got:\n{read:#?}");
+        Ok(())
+    })?;
+
+    let drifted = "ID,Customer__Postal-Code,CLIENT__City\n7,01234,Gdańsk\n";
+    let mapping = HeaderMapping::default()
+        .with_rename("customer", "client")
+        .with_alias("customer__zip", "customer__postal_code")
+        .with_matching(HeaderMatching::Loose);
+    read(&mapping, drifted).and_then(|read| {
+        anyhow::ensure!(read == data, "got:\n{read:#?}");
+        Ok(())
+    })?;
+    anyhow::ensure!(
+        read(&HeaderMapping::default(), drifted).is_err(),
+        "headers should only match exactly by default"
+    );
+
+    // the options apply to the mapped paths whatever order they're set in
+    let mapping = HeaderMapping::default()
+        .with_rename("customer", "client")
+        .with_label("id", "Order ID");
+    let file = "Order ID,client__zip,comment\n7,01234,hi\n";
+    let reader = || {
+        csv::ReaderBuilder::new()
+            .from_reader(file.as_bytes())
+            .enable_nested::<Order>()
+            .context("enabling nesting")
+    };
+    let missing = MissingColumns::default().with_fallback("customer__city", "Gdańsk");
+    [
+        reader()?
+            .with_unknown_columns(UnknownColumns::Skip)
+            .with_missing_columns(missing.clone())
+            .with_header_mapping(mapping.clone()),
+        reader()?
+            .with_header_mapping(mapping.clone())
+            .with_missing_columns(missing.clone())
+            .with_unknown_columns(UnknownColumns::Skip),
+    ]
+    .into_iter()
+    .try_for_each(|mut reader| {
+        let filled = reader.filled_paths().context("filled paths")?.to_vec();
+        anyhow::ensure!(filled == ["customer__city"], "filled: {filled:?}");
+        reader
+            .deserialize()
+            .collect::<Result<Vec<_>, _>>()
+            .context("deserializing")
+            .and_then(|read| {
+                anyhow::ensure!(read == data, "got:\n{read:#?}");
+                Ok(())
+            })
+    })?;
+    reader()?
+        .with_unknown_columns(UnknownColumns::Error)
+        .with_header_mapping(mapping)
+        .deserialize()
+        .next()
+        .context("an error for the unknown column")?
+        .pipe(|read| match read {
+            Err(crate::nested_csv::read::Error::UnknownColumns { columns }) => {
+                anyhow::ensure!(columns == ["comment"], "unknown: {columns:?}");
+                Ok(())
+            }
+            other => anyhow::bail!("unexpected: {other:?}"),
+        })
+}
+
+#[test]
+fn test_missing_columns_are_filled() -> Result<()> {
+    #[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+    #[serde(default)]
+    struct Settings {
+        retries: u32,
+        verbose: bool,
+    }
+
+    #[derive(Deserialize, Debug, Clone, PartialEq)]
+    struct Rate(f64);
+
+    impl Default for Rate {
+        fn default() -> Self {
+            Rate(1.0)
+        }
+    }
+
+    fn eur() -> String {
+        "EUR".into()
+    }
+
+    #[derive(Deserialize, Debug, Clone, PartialEq)]
+    struct Export {
+        name: String,
+        #[serde(default = "eur")]
+        currency: String,
+        #[serde(default)]
+        rate: Rate,
+        settings: Settings,
+        tags: Vec<String>,
+        note: Option<String>,
+    }
+
+    let read = |file: &str, missing: MissingColumns| {
+        csv::ReaderBuilder::new()
+            .from_reader(file.as_bytes())
+            .enable_nested::<Export>()
+            .context("enabling nesting")?
+            .with_missing_columns(missing)
+            .pipe(|mut reader| {
+                reader
+                    .deserialize()
+                    .collect::<Result<Vec<_>, _>>()
+                    .context("deserializing")
+                    .and_then(|read| {
+                        reader
+                            .filled_paths()
+                            .map(|filled| (read, filled.to_vec()))
+                            .context("filled paths")
+                    })
+            })
+    };
+
+    let old_export = "name\nx\n";
+    anyhow::ensure!(
+        read(old_export, MissingColumns::default()).is_err(),
+        "missing columns are an error by default"
+    );
+    // only the defaults the type asks for are used
+    read(old_export, MissingColumns::default().with_defaults()).and_then(|(read, filled)| {
+        anyhow::ensure!(
+            read == [Export {
+                name: "x".into(),
+                currency: "EUR".into(),
+                rate: Rate(1.0),
+                settings: Settings::default(),
+                tags: Vec::new(),
+                note: None,
+            }],
+            "got:\n{read:#?}"
+        );
+        anyhow::ensure!(filled == ["settings", "tags"], "filled: {filled:?}");
+        Ok(())
+    })?;
+    anyhow::ensure!(
+        read("currency\nPLN\n", MissingColumns::default().with_defaults()).is_err(),
+        "a leaf without a default is still an error"
+    );
+
+    read(
+        "name,settings__verbose,tags__idx-0\nx,true,a\n",
+        MissingColumns::default()
+            .with_fallback("currency", "PLN")
+            .with_fallback("rate", "4.5")
+            .with_fallback("settings__retries", "3"),
+    )
+    .and_then(|(read, filled)| {
+        anyhow::ensure!(
+            read == [Export {
+                name: "x".into(),
+                currency: "PLN".into(),
+                rate: Rate(4.5),
+                settings: Settings {
+                    retries: 3,
+                    verbose: true,
+                },
+                tags: vec!["a".into()],
+                note: None,
+            }],
+            "got:\n{read:#?}"
+        );
+        anyhow::ensure!(
+            filled == ["currency", "rate", "settings__retries"],
+            "filled: {filled:?}"
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn test_repeated_headers() -> Result<()> {
+    #[derive(Deserialize, Debug, Clone, PartialEq)]
+    struct Tagged {
+        id: u32,
+        tag: Vec<String>,
+    }
+
+    let file = "id,tag,tag,tag\n1,a,b,c\n";
+    let error = read_nested::<Tagged>(&FlattenConfig::default(), file)
+        .err()
+        .context("repeated headers should be rejected")?;
+    anyhow::ensure!(
+        matches!(
+            error.downcast_ref(),
+            Some(crate::nested_csv::read::Error::DuplicateHeader { header, first: 1, second: 2 })
+                if header == "tag"
+        ),
+        "unexpected error: {error:?}"
+    );
+
+    csv::ReaderBuilder::new()
+        .from_reader(file.as_bytes())
+        .enable_nested::<Tagged>()
+        .context("enabling nesting")?
+        .with_repeated_headers(RepeatedHeaders::Array)
+        .deserialize()
+        .collect::<Result<Vec<_>, _>>()
+        .context("deserializing")
+        .and_then(|read| {
+            anyhow::ensure!(
+                read == [Tagged {
+                    id: 1,
+                    tag: vec!["a".into(), "b".into(), "c".into()],
+                }],
+                "got:\n{read:#?}"
+            );
+            Ok(())
+        })
+}